@@ -0,0 +1,124 @@
+//! Compares `IntervalIndex` queries against a linear scan over a million
+//! intervals, run with `cargo bench --bench index`
+
+use chrono::Duration;
+use libtimew::{parse_timestamp, IntervalIndex, TimeWarriorLine};
+use std::hint::black_box;
+use std::time::{Duration as StdDuration, Instant};
+
+const INTERVALS: i64 = 1_000_000;
+const QUERIES: i64 = 100;
+
+const TAGS: [&str; 5] = ["work", "meeting", "lunch", "review", "travel"];
+
+/// Back to back intervals of 10 to 70 minutes with short gaps, roughly
+/// twenty years of tracking
+fn synthetic_intervals() -> Vec<TimeWarriorLine> {
+    let mut from = parse_timestamp("20000101T000000Z").unwrap();
+    (0..INTERVALS)
+        .map(|i| {
+            let until = from + Duration::minutes(10 + i * 7919 % 60);
+            let interval = TimeWarriorLine::builder(from)
+                .until(until)
+                .tag(TAGS[(i % 5) as usize])
+                .tag(format!("project{}", i % 1000))
+                .build()
+                .unwrap();
+            from = until + Duration::minutes(i % 5);
+            interval
+        })
+        .collect()
+}
+
+fn measure<F: FnMut(i64) -> usize>(name: &str, mut query: F) -> (StdDuration, usize) {
+    let start = Instant::now();
+    let mut matches = 0;
+    for i in 0..QUERIES {
+        matches += black_box(query(black_box(i)));
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<36} {:>10.2?} total {:>12.1} µs/query",
+        name,
+        elapsed,
+        elapsed.as_secs_f64() * 1e6 / QUERIES as f64
+    );
+    (elapsed, matches)
+}
+
+fn compare<I, L>(name: &str, indexed: I, linear: L)
+where
+    I: FnMut(i64) -> usize,
+    L: FnMut(i64) -> usize,
+{
+    let (linear_time, linear_matches) = measure(&format!("{} (linear scan)", name), linear);
+    let (index_time, index_matches) = measure(&format!("{} (index)", name), indexed);
+    assert_eq!(index_matches, linear_matches);
+    println!(
+        "{:<36} {:.0}x faster\n",
+        "",
+        linear_time.as_secs_f64() / index_time.as_secs_f64()
+    );
+}
+
+fn main() {
+    let intervals = synthetic_intervals();
+    let first = intervals[0].from();
+    let span = intervals[intervals.len() - 1].from() - first;
+
+    let start = Instant::now();
+    let index = IntervalIndex::new(intervals.iter().cloned());
+    println!(
+        "indexed {} intervals in {:.2?}\n",
+        index.len(),
+        start.elapsed()
+    );
+
+    // query instants spread over the whole data set
+    let instant = |i: i64| first + span * (i * 37 % QUERIES) as i32 / QUERIES as i32;
+
+    compare(
+        "overlapping one day",
+        |i| {
+            let from = instant(i);
+            index.overlapping(from, from + Duration::days(1)).len()
+        },
+        |i| {
+            let from = instant(i);
+            let until = from + Duration::days(1);
+            intervals
+                .iter()
+                .filter(|interval| {
+                    interval.from() < until && interval.until().is_none_or(|end| end > from)
+                })
+                .count()
+        },
+    );
+
+    compare(
+        "active at instant",
+        |i| index.active_at(instant(i)).len(),
+        |i| {
+            let at = instant(i);
+            intervals
+                .iter()
+                .filter(|interval| {
+                    interval.from() <= at && interval.until().is_none_or(|end| end > at)
+                })
+                .count()
+        },
+    );
+
+    compare(
+        "with tag",
+        |i| index.with_tag(&format!("project{}", i)).count(),
+        |i| {
+            let tag = format!("project{}", i);
+            intervals
+                .iter()
+                .filter(|interval| interval.tags().contains(&tag))
+                .count()
+        },
+    );
+}
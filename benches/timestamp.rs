@@ -0,0 +1,75 @@
+//! Compares `parse_timestamp` against the chrono format string parser it
+//! replaced, run with `cargo bench --bench timestamp`
+
+use chrono::prelude::*;
+use libtimew::parse_timestamp;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 1_000_000;
+
+// the parser libtimew used before `parse_timestamp`
+fn parse_with_format_string(date_string: &str) -> Option<DateTime<Utc>> {
+    let from_part = format!("{} +0000", date_string);
+
+    match DateTime::parse_from_str(&from_part, "%Y%m%dT%H%M%SZ %z") {
+        Ok(a) => Utc.from_local_datetime(&a.naive_local()).single(),
+        Err(_) => None,
+    }
+}
+
+fn measure(
+    name: &str,
+    timestamps: &[String],
+    parse: fn(&str) -> Option<DateTime<Utc>>,
+) -> Duration {
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        let timestamp = &timestamps[i as usize % timestamps.len()];
+        black_box(parse(black_box(timestamp)));
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<20} {:>10.2?} total {:>8.1} ns/timestamp",
+        name,
+        elapsed,
+        elapsed.as_nanos() as f64 / f64::from(ITERATIONS)
+    );
+    elapsed
+}
+
+fn main() {
+    let timestamps: Vec<String> = (0..1000)
+        .map(|i| {
+            format!(
+                "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+                2000 + i % 30,
+                1 + i % 12,
+                1 + i % 28,
+                i % 24,
+                i % 60,
+                (i * 7) % 60
+            )
+        })
+        .collect();
+
+    for timestamp in &timestamps {
+        assert_eq!(
+            parse_timestamp(timestamp),
+            parse_with_format_string(timestamp)
+        );
+    }
+
+    let format_string = measure(
+        "chrono format string",
+        &timestamps,
+        parse_with_format_string,
+    );
+    let fixed_width = measure("parse_timestamp", &timestamps, parse_timestamp);
+
+    println!(
+        "parse_timestamp is {:.1}x faster",
+        format_string.as_secs_f64() / fixed_width.as_secs_f64()
+    );
+}
@@ -0,0 +1,652 @@
+use crate::database::{list_data_files, month_file, DataFile, LegacyCopies};
+use crate::lock::FileLocks;
+use crate::{DatabaseError, TimeWarriorLine, TimeWarriorLineError};
+use chrono::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What is wrong with a line of a data file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Not a valid data line
+    Unparsable(TimeWarriorLineError),
+    /// Sorts before the line above it, timewarrior keeps lines sorted
+    Unsorted,
+    /// The interval belongs in the file of its start month
+    WrongFile { expected: PathBuf },
+    /// The same interval is also at this earlier place
+    Duplicate { path: PathBuf, line_number: usize },
+    /// The interval shares time with this one, which starts earlier
+    Overlap { path: PathBuf, line_number: usize },
+    /// The interval is open while this later one is too
+    MultipleOpen { path: PathBuf, line_number: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Unparsable(error) => write!(f, "{}", error),
+            Problem::Unsorted => write!(f, "line is out of order"),
+            Problem::WrongFile { expected } => {
+                write!(f, "interval belongs in {}", expected.display())
+            }
+            Problem::Duplicate { path, line_number } => {
+                write!(f, "interval duplicates {}:{}", path.display(), line_number)
+            }
+            Problem::Overlap { path, line_number } => {
+                write!(f, "interval overlaps {}:{}", path.display(), line_number)
+            }
+            Problem::MultipleOpen { path, line_number } => write!(
+                f,
+                "interval is open but so is {}:{}",
+                path.display(),
+                line_number
+            ),
+        }
+    }
+}
+
+/// A problem found by `Database::check`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub path: PathBuf,
+    /// 1-based, like editors count lines
+    pub line_number: usize,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.path.display(),
+            self.line_number,
+            self.problem
+        )
+    }
+}
+
+/// The new content of a data file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub before: Vec<String>,
+    /// empty when the file is to be removed
+    pub after: Vec<String>,
+}
+
+/// The changes fixing the problems of a data directory, see
+/// `Database::plan_repair`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repair {
+    data_dir: PathBuf,
+    changes: Vec<FileChange>,
+    unresolved: Vec<Finding>,
+}
+
+impl Repair {
+    pub fn changes(&self) -> &[FileChange] {
+        &self.changes
+    }
+
+    /// The problems left after the repair, with the line numbers the files
+    /// will have then
+    pub fn unresolved(&self) -> &[Finding] {
+        &self.unresolved
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// A unified diff of the changes, for a dry run
+    pub fn diff(&self) -> String {
+        let mut diff = String::new();
+        for change in &self.changes {
+            let (before, after) = (&change.before, &change.after);
+            let prefix = before
+                .iter()
+                .zip(after)
+                .take_while(|(before, after)| before == after)
+                .count();
+            let suffix = before[prefix..]
+                .iter()
+                .rev()
+                .zip(after[prefix..].iter().rev())
+                .take_while(|(before, after)| before == after)
+                .count();
+            let removed = &before[prefix..before.len() - suffix];
+            let added = &after[prefix..after.len() - suffix];
+
+            let path = change.path.display();
+            diff.push_str(&format!("--- {}\n+++ {}\n", path, path));
+            diff.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(prefix, removed.len()),
+                hunk_range(prefix, added.len())
+            ));
+            for line in removed {
+                diff.push_str(&format!("-{}\n", line));
+            }
+            for line in added {
+                diff.push_str(&format!("+{}\n", line));
+            }
+        }
+        diff
+    }
+
+    /// Writes the changes while holding the locks of the files they touch,
+    /// failing if any file changed since the repair was planned
+    pub(crate) fn apply(&self, lock_timeout: Duration) -> Result<(), DatabaseError> {
+        let paths = self.changes.iter().map(|change| change.path.clone());
+        let mut locks = FileLocks::acquire(paths, lock_timeout)?;
+
+        for change in &self.changes {
+            if read_raw(&change.path)? != change.before {
+                return Err(DatabaseError::RepairOutdated {
+                    path: change.path.clone(),
+                });
+            }
+        }
+        for change in &self.changes {
+            if change.after.is_empty() {
+                fs::remove_file(&change.path).map_err(DatabaseError::io(&change.path))?;
+            } else {
+                locks.replace(&change.path, &(change.after.join("\n") + "\n"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `start,count` of a diff hunk, where an empty side names the line before
+fn hunk_range(prefix: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{},0", prefix)
+    } else {
+        format!("{},{}", prefix + 1, count)
+    }
+}
+
+/// A parsed line and where it was found
+struct Entry<'a> {
+    path: &'a Path,
+    line_number: usize,
+    interval: TimeWarriorLine,
+}
+
+/// The year and month of the data file `path`
+fn file_month(path: &Path) -> Option<(i32, u32)> {
+    DataFile::from_path(path.to_owned()).map(|file| file.month())
+}
+
+pub(crate) fn check(data_dir: &Path) -> Result<Vec<Finding>, DatabaseError> {
+    Ok(check_files(data_dir, &read_files(data_dir)?))
+}
+
+fn check_files(data_dir: &Path, files: &BTreeMap<PathBuf, Vec<String>>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut entries = Vec::new();
+
+    for (path, lines) in files {
+        let mut previous: Option<&str> = None;
+        for (index, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut report = |problem| {
+                findings.push(Finding {
+                    path: path.clone(),
+                    line_number: index + 1,
+                    problem,
+                })
+            };
+            if previous.is_some_and(|previous| previous > line.as_str()) {
+                report(Problem::Unsorted);
+            }
+            previous = Some(line);
+
+            match TimeWarriorLine::from_str(line) {
+                Ok(interval) => entries.push(Entry {
+                    path,
+                    line_number: index + 1,
+                    interval,
+                }),
+                Err(error) => report(Problem::Unparsable(error)),
+            }
+        }
+    }
+
+    let mut report = |entry: &Entry, problem| {
+        findings.push(Finding {
+            path: entry.path.to_owned(),
+            line_number: entry.line_number,
+            problem,
+        })
+    };
+
+    // the first copy of an interval is the original, the others duplicates
+    // unless older timewarrior versions put them there
+    let mut seen: HashMap<String, &Entry> = HashMap::new();
+    let mut copies = LegacyCopies::default();
+    let mut unique = Vec::new();
+    for entry in &entries {
+        if file_month(entry.path).is_some_and(|month| copies.is_copy(month, &entry.interval)) {
+            continue;
+        }
+        match seen.get(&entry.interval.to_string()) {
+            Some(original) => report(
+                entry,
+                Problem::Duplicate {
+                    path: original.path.to_owned(),
+                    line_number: original.line_number,
+                },
+            ),
+            None => {
+                seen.insert(entry.interval.to_string(), entry);
+                unique.push(entry);
+            }
+        }
+    }
+
+    for entry in &unique {
+        let expected = month_file(data_dir, entry.interval.from());
+        if entry.path != expected {
+            report(entry, Problem::WrongFile { expected });
+        }
+    }
+
+    unique.sort_by_key(|entry| entry.interval.from());
+    if let Some(latest_open) = unique.iter().rev().find(|entry| entry.interval.is_active()) {
+        let latest_open = *latest_open;
+        for entry in &unique {
+            if entry.interval.is_active() && !std::ptr::eq(*entry, latest_open) {
+                report(
+                    entry,
+                    Problem::MultipleOpen {
+                        path: latest_open.path.to_owned(),
+                        line_number: latest_open.line_number,
+                    },
+                );
+            }
+        }
+        // open intervals other than the latest are already reported
+        unique.retain(|entry| !entry.interval.is_active() || std::ptr::eq(*entry, latest_open));
+    }
+
+    // the interval reaching furthest so far
+    let mut furthest: Option<&Entry> = None;
+    for entry in unique {
+        if let Some(earlier) = furthest {
+            if ends_after(&earlier.interval, entry.interval.from()) {
+                report(
+                    entry,
+                    Problem::Overlap {
+                        path: earlier.path.to_owned(),
+                        line_number: earlier.line_number,
+                    },
+                );
+            }
+            if ends_later(&entry.interval, &earlier.interval) {
+                furthest = Some(entry);
+            }
+        } else {
+            furthest = Some(entry);
+        }
+    }
+
+    findings.sort_by(|a, b| (&a.path, a.line_number).cmp(&(&b.path, b.line_number)));
+    findings
+}
+
+pub(crate) fn plan(data_dir: &Path) -> Result<Repair, DatabaseError> {
+    let files = read_files(data_dir)?;
+
+    // lines kept as they are because not even lenient parsing understands them
+    let mut repaired: BTreeMap<PathBuf, Vec<String>> = files
+        .keys()
+        .map(|path| (path.clone(), Vec::new()))
+        .collect();
+    // the text to write for each interval, its original line if unchanged
+    let mut intervals: Vec<(TimeWarriorLine, Option<String>)> = Vec::new();
+    let mut seen = HashSet::new();
+    // copies older timewarrior versions made, kept while the original is
+    let mut copies = LegacyCopies::default();
+    let mut copied = Vec::new();
+
+    for (path, lines) in &files {
+        for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+            let (interval, text) = match TimeWarriorLine::from_str(line) {
+                Ok(interval) => (interval, Some(line.clone())),
+                Err(_) => match TimeWarriorLine::parse_lenient(line) {
+                    Ok(interval) => (interval, None),
+                    Err(_) => {
+                        repaired.entry(path.clone()).or_default().push(line.clone());
+                        continue;
+                    }
+                },
+            };
+            if file_month(path).is_some_and(|month| copies.is_copy(month, &interval)) {
+                copied.push((path.clone(), interval, line.clone()));
+            } else if seen.insert(interval.to_string()) {
+                intervals.push((interval, text));
+            }
+        }
+    }
+
+    // close open intervals and shorten overlapping ones where the later
+    // interval starts; intervals inside a longer one are left for the user
+    intervals.sort_by_key(|(interval, _)| interval.from());
+    let mut furthest: Option<usize> = None;
+    for index in 0..intervals.len() {
+        let earlier = match furthest {
+            Some(earlier) => earlier,
+            None => {
+                furthest = Some(index);
+                continue;
+            }
+        };
+        let current = intervals[index].0.clone();
+        let (interval, text) = &mut intervals[earlier];
+        if ends_after(interval, current.from())
+            && interval.from() < current.from()
+            && (interval.is_active() || !ends_later(interval, &current))
+        {
+            *interval = TimeWarriorLine {
+                until: Some(current.from()),
+                ..interval.clone()
+            };
+            *text = None;
+        }
+        if ends_later(&current, interval) {
+            furthest = Some(index);
+        }
+    }
+
+    let unchanged: HashSet<&TimeWarriorLine> = intervals
+        .iter()
+        .filter(|(_, text)| text.is_some())
+        .map(|(interval, _)| interval)
+        .collect();
+    for (path, interval, line) in copied {
+        if unchanged.contains(&interval) {
+            repaired.entry(path).or_default().push(line);
+        }
+    }
+
+    for (interval, text) in intervals {
+        let text = text.unwrap_or_else(|| interval.to_string());
+        repaired
+            .entry(month_file(data_dir, interval.from()))
+            .or_default()
+            .push(text);
+    }
+
+    let mut changes = Vec::new();
+    for (path, after) in &mut repaired {
+        after.sort();
+        let before = files.get(path).cloned().unwrap_or_default();
+        if &before != after {
+            changes.push(FileChange {
+                path: path.clone(),
+                before,
+                after: after.clone(),
+            });
+        }
+    }
+
+    Ok(Repair {
+        data_dir: data_dir.to_owned(),
+        unresolved: check_files(data_dir, &repaired),
+        changes,
+    })
+}
+
+/// Whether `interval` still runs after `instant`, open ones run forever
+fn ends_after(interval: &TimeWarriorLine, instant: DateTime<Utc>) -> bool {
+    interval.until().is_none_or(|until| until > instant)
+}
+
+/// Whether `interval` ends after `other`
+fn ends_later(interval: &TimeWarriorLine, other: &TimeWarriorLine) -> bool {
+    match (interval.until(), other.until()) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(until), Some(other)) => until > other,
+    }
+}
+
+fn read_files(data_dir: &Path) -> Result<BTreeMap<PathBuf, Vec<String>>, DatabaseError> {
+    let mut files = BTreeMap::new();
+    for file in list_data_files(data_dir)? {
+        let lines = read_raw(&file.path)?;
+        files.insert(file.path, lines);
+    }
+    Ok(files)
+}
+
+/// All lines of a file including blank ones, a missing file has none
+fn read_raw(path: &Path) -> Result<Vec<String>, DatabaseError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().map(str::to_owned).collect()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(DatabaseError::io(path)(error)),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::{interval, TempDir};
+    use crate::Database;
+
+    fn finding(path: &Path, line_number: usize, problem: Problem) -> Finding {
+        Finding {
+            path: path.to_owned(),
+            line_number,
+            problem,
+        }
+    }
+
+    #[test]
+    fn healthy_database_has_no_findings() {
+        let dir = TempDir::new("check-healthy");
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # a\ninc 20200201T090000Z # b\n",
+        );
+        let database = Database::open(dir.path().join("data")).unwrap();
+
+        assert_eq!(database.check().unwrap(), vec![]);
+        assert_eq!(database.plan_repair().unwrap().is_empty(), true);
+    }
+
+    #[test]
+    fn legacy_copies_of_month_spanning_intervals_are_no_duplicates() {
+        let dir = TempDir::new("check-legacy");
+        let line = "inc 20200131T220000Z - 20200301T020000Z # long\n";
+        dir.write("data/2020-01.data", line);
+        dir.write("data/2020-02.data", line);
+        dir.write(
+            "data/2020-03.data",
+            &format!("{}inc 20200302T080000Z # other\n", line),
+        );
+        let database = Database::open(dir.path().join("data")).unwrap();
+
+        assert_eq!(database.check().unwrap(), vec![]);
+        assert_eq!(database.plan_repair().unwrap().is_empty(), true);
+    }
+
+    #[test]
+    fn finds_each_class_of_problem() {
+        let dir = TempDir::new("check-problems");
+        let february = dir.write(
+            "data/2020-02.data",
+            "inc 20200203T080000Z - 20200203T090000Z # late\n\
+             inc 20200201T080000Z - 20200201T100000Z # early\n\
+             inc 20200201T090000Z - 20200201T110000Z # overlap\n\
+             inc 20200204T080000Z # first open\n\
+             inc broken\n",
+        );
+        let march = dir.write(
+            "data/2020-03.data",
+            "inc 20200203T080000Z - 20200203T090000Z # late\n\
+             inc 20200205T080000Z # second open\n\
+             inc 20200301T080000Z - 20200301T090000Z # march\n",
+        );
+        let database = Database::open(dir.path().join("data")).unwrap();
+
+        assert_eq!(
+            database.check().unwrap(),
+            vec![
+                finding(&february, 2, Problem::Unsorted),
+                finding(
+                    &february,
+                    3,
+                    Problem::Overlap {
+                        path: february.clone(),
+                        line_number: 2,
+                    }
+                ),
+                finding(
+                    &february,
+                    4,
+                    Problem::MultipleOpen {
+                        path: march.clone(),
+                        line_number: 2,
+                    }
+                ),
+                finding(
+                    &february,
+                    5,
+                    Problem::Unparsable(TimeWarriorLineError::BadStart {
+                        offset: 4,
+                        token: "broken".to_owned(),
+                    })
+                ),
+                finding(
+                    &march,
+                    1,
+                    Problem::Duplicate {
+                        path: february.clone(),
+                        line_number: 1,
+                    }
+                ),
+                finding(
+                    &march,
+                    2,
+                    Problem::WrongFile {
+                        expected: february.clone(),
+                    }
+                ),
+                finding(
+                    &march,
+                    3,
+                    Problem::Overlap {
+                        path: march.clone(),
+                        line_number: 2,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            database.check().unwrap()[0].to_string(),
+            format!("{}:2: line is out of order", february.display())
+        );
+    }
+
+    #[test]
+    fn repair_plans_a_dry_run_and_applies_it() {
+        let dir = TempDir::new("check-repair");
+        let february = dir.write(
+            "data/2020-02.data",
+            "inc 20200203T080000Z - 20200203T090000Z # late\n\
+             inc 20200201T080000Z - 20200201T100000Z # early\n\
+             inc 20200201T090000Z - 20200201T110000Z # overlap\n\
+             inc 20200202T080000Z - 20200202T090000Z # \"unterminated\n\
+             inc broken\n",
+        );
+        let march = dir.write(
+            "data/2020-03.data",
+            "inc 20200203T080000Z - 20200203T090000Z # late\n\
+             inc 20200204T080000Z # first open\n\
+             inc 20200301T080000Z # second open\n",
+        );
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        let repair = database.plan_repair().unwrap();
+        assert_eq!(
+            repair.diff(),
+            format!(
+                "--- {0}\n+++ {0}\n@@ -1,4 +1,5 @@\n\
+                 -inc 20200203T080000Z - 20200203T090000Z # late\n\
+                 -inc 20200201T080000Z - 20200201T100000Z # early\n\
+                 -inc 20200201T090000Z - 20200201T110000Z # overlap\n\
+                 -inc 20200202T080000Z - 20200202T090000Z # \"unterminated\n\
+                 +inc 20200201T080000Z - 20200201T090000Z # early\n\
+                 +inc 20200201T090000Z - 20200201T110000Z # overlap\n\
+                 +inc 20200202T080000Z - 20200202T090000Z # unterminated\n\
+                 +inc 20200203T080000Z - 20200203T090000Z # late\n\
+                 +inc 20200204T080000Z - 20200301T080000Z # first open\n\
+                 --- {1}\n+++ {1}\n@@ -1,2 +0,0 @@\n\
+                 -inc 20200203T080000Z - 20200203T090000Z # late\n\
+                 -inc 20200204T080000Z # first open\n",
+                february.display(),
+                march.display()
+            )
+        );
+        assert_eq!(
+            repair.unresolved(),
+            &[finding(
+                &february,
+                6,
+                Problem::Unparsable(TimeWarriorLineError::BadStart {
+                    offset: 4,
+                    token: "broken".to_owned(),
+                })
+            )]
+        );
+
+        database.set_journal_size(Some(0));
+        database.repair(&repair).unwrap();
+        assert_eq!(database.check().unwrap(), repair.unresolved());
+        assert_eq!(database.intervals().count(), 6);
+        assert_eq!(
+            dir.read("data/2020-03.data"),
+            "inc 20200301T080000Z # second open\n"
+        );
+
+        database
+            .add(interval("inc 20200401T080000Z - 20200401T090000Z"))
+            .unwrap();
+        assert_eq!(dir.read("data/undo.data"), "");
+    }
+
+    #[test]
+    fn repair_refuses_files_changed_since_planning() {
+        let dir = TempDir::new("check-outdated");
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200202T080000Z - 20200202T090000Z # b\ninc 20200201T080000Z - 20200201T090000Z # a\n",
+        );
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+        let repair = database.plan_repair().unwrap();
+
+        dir.write("data/2020-02.data", "inc 20200205T080000Z # c\n");
+
+        let result = database.repair(&repair);
+        assert_eq!(
+            matches!(result, Err(DatabaseError::RepairOutdated { .. })),
+            true,
+            "{:?}",
+            result
+        );
+        assert_eq!(dir.read("data/2020-02.data"), "inc 20200205T080000Z # c\n");
+    }
+}
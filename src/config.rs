@@ -0,0 +1,413 @@
+use crate::DatabaseError;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A line that is neither a setting, a block, an import nor a comment
+    Syntax { line_number: usize, line: String },
+    /// A file imports itself, directly or through other files
+    ImportCycle { line_number: usize, import: PathBuf },
+    /// An import in configuration parsed from a string, which has no
+    /// location to resolve it against
+    UnresolvedImport { line_number: usize, import: PathBuf },
+    /// Not a dotted key like `reports.day.hours`
+    InvalidKey(String),
+    /// A setting has a value timewarrior would reject
+    InvalidValue {
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Syntax { line_number, line } => {
+                write!(f, "line {}: unrecognized construct {:?}", line_number, line)
+            }
+            ConfigError::ImportCycle {
+                line_number,
+                import,
+            } => write!(
+                f,
+                "line {}: {} is already being imported",
+                line_number,
+                import.display()
+            ),
+            ConfigError::UnresolvedImport {
+                line_number,
+                import,
+            } => write!(
+                f,
+                "line {}: can not import {} without a config file",
+                line_number,
+                import.display()
+            ),
+            ConfigError::InvalidKey(key) => write!(f, "{:?} is not a valid key", key),
+            ConfigError::InvalidValue {
+                key,
+                value,
+                expected,
+            } => write!(f, "{} = {:?} is invalid, expected {}", key, value, expected),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// The settings of `timewarrior.cfg` and the files it imports
+///
+/// Keys are flattened to their dotted form, so the block
+///
+/// ```text
+/// define reports:
+///   day:
+///     hours = auto
+/// ```
+///
+/// is the same as `reports.day.hours = auto`. When a key is set more than
+/// once the last value wins, like in timewarrior.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Config {
+    settings: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Reads a config file and its imports, a missing file has no settings
+    pub fn load(path: &Path) -> Result<Config, DatabaseError> {
+        let mut config = Config::default();
+        match fs::read_to_string(path) {
+            Ok(content) => config.include(path, &content, &mut Vec::new())?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(DatabaseError::io(path)(error)),
+        }
+        Ok(config)
+    }
+
+    /// The raw value of a setting
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.settings.get(key).map(String::as_str)
+    }
+
+    /// All settings ordered by key
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.settings
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// The settings below `prefix`, with the prefix and its dot removed
+    ///
+    /// `section("exclusions")` yields `("monday", "<8:00 >17:00")` for the
+    /// setting `exclusions.monday`.
+    pub fn section<'a>(&'a self, prefix: &str) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        let prefix = format!("{}.", prefix);
+        let skip = prefix.len();
+        self.settings
+            .range(prefix.clone()..)
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .map(move |(key, value)| (&key[skip..], value.as_str()))
+    }
+
+    /// Parses `content` as the file `path` and follows its imports,
+    /// `loading` holds the canonical paths of the files being read to
+    /// detect cycles
+    fn include(
+        &mut self,
+        path: &Path,
+        content: &str,
+        loading: &mut Vec<PathBuf>,
+    ) -> Result<(), DatabaseError> {
+        let config_error = |error| DatabaseError::Config {
+            path: path.to_owned(),
+            error,
+        };
+        loading.push(fs::canonicalize(path).map_err(DatabaseError::io(path))?);
+
+        let lines = scan(content).map_err(config_error)?;
+        self.add(content, lines, |config, line_number, import| {
+            let import = resolve_import(path, &import);
+            let canonical = fs::canonicalize(&import).map_err(DatabaseError::io(&import))?;
+            if loading.contains(&canonical) {
+                return Err(config_error(ConfigError::ImportCycle {
+                    line_number,
+                    import,
+                }));
+            }
+            let content = fs::read_to_string(&import).map_err(DatabaseError::io(&import))?;
+            config.include(&import, &content, loading)
+        })?;
+
+        loading.pop();
+        Ok(())
+    }
+
+    /// Takes over the settings of the scanned `lines` of `content`, passing
+    /// imports with their line number to `import`
+    fn add<E>(
+        &mut self,
+        content: &str,
+        lines: Vec<Line>,
+        mut import: impl FnMut(&mut Config, usize, String) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for (index, (line, raw)) in lines.into_iter().zip(content.lines()).enumerate() {
+            match line {
+                Line::Setting { key, value } => {
+                    self.settings.insert(key, raw[value].to_owned());
+                }
+                Line::Import(path) => import(self, index + 1, path)?,
+                Line::Empty | Line::Block { .. } => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    /// Parses configuration without imports, see `Config::load` for files
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+        let lines = scan(content)?;
+        config.add(content, lines, |_, line_number, import| {
+            Err(ConfigError::UnresolvedImport {
+                line_number,
+                import: PathBuf::from(import),
+            })
+        })?;
+        Ok(config)
+    }
+}
+
+/// What a line of a config file holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Line {
+    /// Blank or only a comment
+    Empty,
+    Import(String),
+    /// A setting with its full key and the byte range of its value
+    Setting {
+        key: String,
+        value: Range<usize>,
+    },
+    /// The start of a block with its full name and indentation
+    Block {
+        name: String,
+        indent: usize,
+    },
+}
+
+/// Classifies every line of `content`, resolving blocks to dotted keys
+pub(crate) fn scan(content: &str) -> Result<Vec<Line>, ConfigError> {
+    let mut lines = Vec::new();
+    // indentation and full name of the enclosing blocks
+    let mut blocks: Vec<(usize, String)> = Vec::new();
+
+    for (index, raw) in content.lines().enumerate() {
+        let line = match raw.find('#') {
+            Some(comment) => &raw[..comment],
+            None => raw,
+        };
+        let text = line.trim();
+        if text.is_empty() {
+            lines.push(Line::Empty);
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        while blocks.last().is_some_and(|(outer, _)| *outer >= indent) {
+            blocks.pop();
+        }
+        let full_key = |key: &str| match blocks.last() {
+            Some((_, block)) => format!("{}.{}", block, key),
+            None => key.to_owned(),
+        };
+
+        if let Some(import) = text.strip_prefix("import ") {
+            lines.push(Line::Import(import.trim().to_owned()));
+        } else if let Some(equals) = line
+            .find('=')
+            .filter(|&equals| !line[..equals].trim().is_empty())
+        {
+            let value = &line[equals + 1..];
+            let start = equals + 1 + value.len() - value.trim_start().len();
+            let end = (equals + 1 + value.trim_end().len()).max(start);
+            lines.push(Line::Setting {
+                key: full_key(line[..equals].trim()),
+                value: start..end,
+            });
+        } else if let Some(name) = text.strip_suffix(':') {
+            let name = full_key(name.strip_prefix("define ").unwrap_or(name).trim());
+            blocks.push((indent, name.clone()));
+            lines.push(Line::Block { name, indent });
+        } else {
+            return Err(ConfigError::Syntax {
+                line_number: index + 1,
+                line: raw.to_owned(),
+            });
+        }
+    }
+    Ok(lines)
+}
+
+/// Expands `~` and makes relative imports relative to the importing file
+fn resolve_import(importing: &Path, import: &str) -> PathBuf {
+    let path = match import.strip_prefix("~/") {
+        Some(rest) => match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(rest),
+            None => PathBuf::from(import),
+        },
+        None => PathBuf::from(import),
+    };
+    match importing.parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn parses_settings_blocks_and_comments() {
+        let config: Config = "# my settings
+verbose = off
+reports.day.hours = auto   # only worked hours
+
+define exclusions:
+  monday = <8:00 12:00-12:45 >17:00
+  days:
+    2020_12_24 = off
+reports:
+  week:
+    range=
+holidays.de-DE:
+  2020_01_01 = Neujahr
+verbose = on
+"
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            config.iter().collect::<Vec<_>>(),
+            vec![
+                ("exclusions.days.2020_12_24", "off"),
+                ("exclusions.monday", "<8:00 12:00-12:45 >17:00"),
+                ("holidays.de-DE.2020_01_01", "Neujahr"),
+                ("reports.day.hours", "auto"),
+                ("reports.week.range", ""),
+                ("verbose", "on"),
+            ]
+        );
+        assert_eq!(config.get("missing"), None);
+    }
+
+    #[test]
+    fn lists_sections() {
+        let config: Config =
+            "exclusions.monday = <8:00\nexclusions.days.2020_12_24 = off\nexclusionsx = 1\n"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            config.section("exclusions").collect::<Vec<_>>(),
+            vec![("days.2020_12_24", "off"), ("monday", "<8:00")]
+        );
+        assert_eq!(config.section("exclusions.days").count(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_constructs() {
+        assert_eq!(
+            "verbose = on\nnonsense\n".parse::<Config>(),
+            Err(ConfigError::Syntax {
+                line_number: 2,
+                line: "nonsense".to_owned(),
+            })
+        );
+        assert_eq!(
+            "import other.cfg\n".parse::<Config>(),
+            Err(ConfigError::UnresolvedImport {
+                line_number: 1,
+                import: PathBuf::from("other.cfg"),
+            })
+        );
+    }
+
+    #[test]
+    fn loads_imports_relative_to_the_file() {
+        let dir = TempDir::new("config-import");
+        let path = dir.write(
+            "timewarrior.cfg",
+            "verbose = on\nimport themes/dark.theme\ndebug = on\n",
+        );
+        dir.write(
+            "themes/dark.theme",
+            "define theme:\n  palette:\n    color01 = white on red\nverbose = off\n",
+        );
+
+        let config = Config::load(&path).unwrap();
+
+        assert_eq!(config.get("theme.palette.color01"), Some("white on red"));
+        assert_eq!(config.get("verbose"), Some("off"));
+        assert_eq!(config.get("debug"), Some("on"));
+        assert_eq!(
+            Config::load(&dir.path().join("missing.cfg")).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn detects_import_cycles() {
+        let dir = TempDir::new("config-cycle");
+        let path = dir.write("timewarrior.cfg", "import a.cfg\n");
+        let a = dir.write("a.cfg", "x = 1\nimport timewarrior.cfg\n");
+
+        match Config::load(&path) {
+            Err(DatabaseError::Config {
+                path: failing,
+                error,
+            }) => {
+                assert_eq!(failing, a);
+                assert_eq!(
+                    error,
+                    ConfigError::ImportCycle {
+                        line_number: 2,
+                        import: path,
+                    }
+                );
+            }
+            result => panic!("expected an import cycle, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn detects_imports_of_the_same_file_through_other_paths() {
+        let dir = TempDir::new("config-self");
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        let import = format!("../{}/timewarrior.cfg", name);
+        let path = dir.write("timewarrior.cfg", &format!("import {}\n", import));
+
+        match Config::load(&path) {
+            Err(DatabaseError::Config { error, .. }) => assert_eq!(
+                error,
+                ConfigError::ImportCycle {
+                    line_number: 1,
+                    import: dir.path().join(import),
+                }
+            ),
+            result => panic!("expected an import cycle, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use crate::config::{scan, Line};
+use crate::lock::DEFAULT_LOCK_TIMEOUT;
+use crate::{ConfigError, DatabaseError, FileLock};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A config file as written, for changing settings the way
+/// `timew config <key> <value>` does
+///
+/// Edits only touch the lines of the settings they change, so comments,
+/// blank lines, ordering and indentation stay as the user wrote them.
+/// Imports are kept but not followed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConfigFile {
+    lines: Vec<String>,
+    scanned: Vec<Line>,
+    /// whether the last line ends with a line break
+    trailing_newline: bool,
+    /// whether lines end in `\r\n` rather than `\n`, as the first one does
+    crlf: bool,
+}
+
+impl ConfigFile {
+    /// Reads a config file, a missing file is empty
+    pub fn load(path: &Path) -> Result<ConfigFile, DatabaseError> {
+        match fs::read_to_string(path) {
+            Ok(content) => content.parse().map_err(|error| DatabaseError::Config {
+                path: path.to_owned(),
+                error,
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(ConfigFile::default()),
+            Err(error) => Err(DatabaseError::io(path)(error)),
+        }
+    }
+
+    /// Replaces the file atomically while holding its lock, see `FileLock`
+    pub fn save(&self, path: &Path) -> Result<(), DatabaseError> {
+        FileLock::acquire(path, DEFAULT_LOCK_TIMEOUT)?.replace(&self.to_string())
+    }
+
+    /// The value this file sets for `key`, the last one if set twice
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.position(key)
+            .and_then(|index| match &self.scanned[index] {
+                Line::Setting { value, .. } => Some(&self.lines[index][value.clone()]),
+                _ => None,
+            })
+    }
+
+    /// Changes the value of `key` in place, or adds the setting
+    ///
+    /// A new setting goes to the end of the deepest block its key lies in,
+    /// indented like the lines already there, or to the end of the file as
+    /// `key = value`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        validate_key(key)?;
+        if value.contains(['#', '\n', '\r']) {
+            return Err(ConfigError::InvalidValue {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                expected: "a value without comments or line breaks",
+            });
+        }
+
+        match self.position(key) {
+            Some(index) => {
+                if let Line::Setting { value: range, .. } = &self.scanned[index] {
+                    let line = &mut self.lines[index];
+                    // `key =` has nothing to separate the new value from
+                    let value = if range.is_empty() && !line[..range.start].ends_with(' ') {
+                        format!(" {}", value)
+                    } else {
+                        value.to_owned()
+                    };
+                    line.replace_range(range.clone(), &value);
+                }
+            }
+            None => {
+                if self.lines.is_empty() {
+                    self.trailing_newline = true;
+                }
+                let (index, line) = self.placement(key, value);
+                self.lines.insert(index, line);
+            }
+        }
+        self.rescan();
+        Ok(())
+    }
+
+    /// Removes every line setting `key`, returning whether there was one
+    ///
+    /// Blocks left empty stay, together with their comments.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let before = self.lines.len();
+        let mut index = 0;
+        let scanned = &self.scanned;
+        self.lines.retain(|_| {
+            let keep = !matches!(&scanned[index], Line::Setting { key: k, .. } if k == key);
+            index += 1;
+            keep
+        });
+        let removed = self.lines.len() != before;
+        if removed {
+            self.rescan();
+        }
+        removed
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.scanned
+            .iter()
+            .rposition(|line| matches!(line, Line::Setting { key: k, .. } if k == key))
+    }
+
+    /// Where to insert a new setting and the line to insert there
+    fn placement(&self, key: &str, value: &str) -> (usize, String) {
+        // the deepest block the key lies in
+        let block = self
+            .scanned
+            .iter()
+            .enumerate()
+            .filter_map(|(index, line)| match line {
+                Line::Block { name, indent } if key.starts_with(&format!("{}.", name)) => {
+                    Some((index, name.len(), *indent))
+                }
+                _ => None,
+            })
+            .max_by_key(|(_, length, _)| *length);
+
+        let (start, length, block_indent) = match block {
+            Some(block) => block,
+            None => return (self.lines.len(), format!("{} = {}", key, value)),
+        };
+
+        // the block ends before the first line indented no deeper than it
+        let mut last = start;
+        let mut indent = None;
+        for index in start + 1..self.lines.len() {
+            if self.scanned[index] == Line::Empty {
+                continue;
+            }
+            let line = &self.lines[index];
+            let line_indent = line.len() - line.trim_start().len();
+            if line_indent <= block_indent {
+                break;
+            }
+            indent.get_or_insert_with(|| line[..line_indent].to_owned());
+            last = index;
+        }
+        let indent = indent.unwrap_or_else(|| {
+            let block_line = &self.lines[start];
+            format!("{}  ", &block_line[..block_indent])
+        });
+        (
+            last + 1,
+            format!("{}{} = {}", indent, &key[length + 1..], value),
+        )
+    }
+
+    fn rescan(&mut self) {
+        // edits keep every line valid, so scanning can not fail
+        if let Ok(scanned) = scan(&self.lines.join("\n")) {
+            self.scanned = scanned;
+        }
+    }
+}
+
+fn validate_key(key: &str) -> Result<(), ConfigError> {
+    let valid = !key.is_empty()
+        && key.split('.').all(|part| {
+            !part.is_empty() && !part.contains(|c: char| c.is_whitespace() || "=#:".contains(c))
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::InvalidKey(key.to_owned()))
+    }
+}
+
+impl FromStr for ConfigFile {
+    type Err = ConfigError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        Ok(ConfigFile {
+            lines: content.lines().map(str::to_owned).collect(),
+            scanned: scan(content)?,
+            trailing_newline: content.ends_with('\n'),
+            crlf: content
+                .find('\n')
+                .is_some_and(|end| content[..end].ends_with('\r')),
+        })
+    }
+}
+
+impl fmt::Display for ConfigFile {
+    /// Writes the file back, byte for byte where nothing was changed
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        for (index, line) in self.lines.iter().enumerate() {
+            f.write_str(line)?;
+            if index + 1 < self.lines.len() || self.trailing_newline {
+                f.write_str(newline)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::Config;
+
+    const SAMPLE: &str = "# Timewarrior configuration
+import ~/.timewarrior/themes/dark.theme
+
+verbose = on   # chatty
+
+define exclusions:
+    monday = <8:00 >17:00
+    # no friday yet
+    days:
+        2020_12_24 = off
+
+reports:
+\tday:
+\t\thours = auto
+\t\trange=
+\tweek.totals = yes
+";
+
+    fn edit(f: impl FnOnce(&mut ConfigFile)) -> String {
+        let mut file: ConfigFile = SAMPLE.parse().unwrap();
+        f(&mut file);
+        file.to_string()
+    }
+
+    #[test]
+    fn round_trips_unchanged() {
+        let file: ConfigFile = SAMPLE.parse().unwrap();
+
+        assert_eq!(file.to_string(), SAMPLE);
+        assert_eq!(
+            "a = 1\n\n\nb = 2"
+                .parse::<ConfigFile>()
+                .unwrap()
+                .to_string(),
+            "a = 1\n\n\nb = 2"
+        );
+        assert_eq!(file.get("exclusions.days.2020_12_24"), Some("off"));
+        assert_eq!(file.get("reports.day.range"), Some(""));
+    }
+
+    #[test]
+    fn keeps_windows_line_endings() {
+        let crlf = SAMPLE.replace('\n', "\r\n");
+        let mut file: ConfigFile = crlf.parse().unwrap();
+        assert_eq!(file.to_string(), crlf);
+        assert_eq!(file.get("verbose"), Some("on"));
+
+        file.set("verbose", "off").unwrap();
+        file.set("debug", "on").unwrap();
+
+        let expected = SAMPLE.replace("verbose = on ", "verbose = off ") + "debug = on\n";
+        assert_eq!(file.to_string(), expected.replace('\n', "\r\n"));
+    }
+
+    #[test]
+    fn updates_values_in_place() {
+        assert_eq!(
+            edit(|file| file.set("verbose", "off").unwrap()),
+            SAMPLE.replace("verbose = on   # chatty", "verbose = off   # chatty")
+        );
+        assert_eq!(
+            edit(|file| file.set("reports.day.range", ":week").unwrap()),
+            SAMPLE.replace("range=", "range= :week")
+        );
+        assert_eq!(
+            edit(|file| file
+                .set("exclusions.days.2020_12_24", "8:00-12:00")
+                .unwrap()),
+            SAMPLE.replace("2020_12_24 = off", "2020_12_24 = 8:00-12:00")
+        );
+    }
+
+    #[test]
+    fn inserts_into_the_deepest_block() {
+        assert_eq!(
+            edit(|file| file.set("exclusions.friday", "<9:00").unwrap()),
+            SAMPLE.replace(
+                "        2020_12_24 = off\n",
+                "        2020_12_24 = off\n    friday = <9:00\n"
+            )
+        );
+        assert_eq!(
+            edit(|file| file.set("reports.day.lines", "2").unwrap()),
+            SAMPLE.replace("\t\trange=\n", "\t\trange=\n\t\tlines = 2\n")
+        );
+        assert_eq!(
+            edit(|file| file.set("debug", "on").unwrap()),
+            format!("{}debug = on\n", SAMPLE)
+        );
+        assert_eq!(
+            "verbose = on".parse::<ConfigFile>().map(|mut file| {
+                file.set("debug", "on").unwrap();
+                file.to_string()
+            }),
+            Ok("verbose = on\ndebug = on".to_owned())
+        );
+    }
+
+    #[test]
+    fn new_settings_parse_back() {
+        let mut file: ConfigFile = SAMPLE.replace("import", "# import").parse().unwrap();
+        file.set("exclusions.days.2020_12_31", "<12:00").unwrap();
+        file.set("reports.month.totals", "on").unwrap();
+
+        let config: Config = file.to_string().parse().unwrap();
+        assert_eq!(config.get("exclusions.days.2020_12_31"), Some("<12:00"));
+        assert_eq!(config.get("exclusions.days.2020_12_24"), Some("off"));
+        assert_eq!(config.get("reports.month.totals"), Some("on"));
+        assert_eq!(config.get("reports.week.totals"), Some("yes"));
+    }
+
+    #[test]
+    fn removes_settings() {
+        let mut file: ConfigFile = SAMPLE.parse().unwrap();
+
+        assert_eq!(file.remove("exclusions.monday"), true);
+        assert_eq!(file.remove("exclusions.monday"), false);
+        assert_eq!(
+            file.to_string(),
+            SAMPLE.replace("    monday = <8:00 >17:00\n", "")
+        );
+        assert_eq!(file.get("exclusions.days.2020_12_24"), Some("off"));
+    }
+
+    #[test]
+    fn rejects_invalid_keys_and_values() {
+        let mut file = ConfigFile::default();
+
+        assert_eq!(
+            file.set("bad key", "x"),
+            Err(ConfigError::InvalidKey("bad key".to_owned()))
+        );
+        assert_eq!(
+            file.set("reports..range", "x"),
+            Err(ConfigError::InvalidKey("reports..range".to_owned()))
+        );
+        assert_eq!(file.set("verbose", "on # off").is_err(), true);
+        assert_eq!(file.to_string(), "");
+
+        file.set("verbose", "off").unwrap();
+        assert_eq!(file.to_string(), "verbose = off\n");
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = TempDir::new("config-file");
+        let path = dir.write("timewarrior.cfg", SAMPLE);
+
+        let mut file = ConfigFile::load(&path).unwrap();
+        file.set("verbose", "off").unwrap();
+        file.save(&path).unwrap();
+
+        assert_eq!(ConfigFile::load(&path).unwrap().get("verbose"), Some("off"));
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["timewarrior.cfg"]);
+        assert_eq!(
+            ConfigFile::load(&dir.path().join("missing.cfg")).unwrap(),
+            ConfigFile::default()
+        );
+    }
+}
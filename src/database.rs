@@ -0,0 +1,1360 @@
+use crate::check::{self, Finding, Repair};
+use crate::lock::{FileLocks, DEFAULT_LOCK_TIMEOUT};
+use crate::{
+    Config, ConfigError, IntervalIndex, Journal, JournalError, ParseOptions, TagsDataError,
+    TimeWarriorLine, TimeWarriorLineError, Transaction, UndoAction,
+};
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration as StdDuration;
+
+/// Where timewarrior keeps its configuration and its data files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// the directory containing `timewarrior.cfg`
+    pub config_dir: PathBuf,
+    /// the directory containing the `YYYY-MM.data` files
+    pub data_dir: PathBuf,
+}
+
+impl Location {
+    /// Finds the database the same way timewarrior does
+    ///
+    /// `TIMEWARRIORDB` wins, then `~/.timewarrior` if it exists, then the XDG
+    /// directories `$XDG_CONFIG_HOME/timewarrior` and
+    /// `$XDG_DATA_HOME/timewarrior/data`.
+    pub fn from_env() -> Option<Location> {
+        Location::from_vars(|name| env::var_os(name).map(PathBuf::from))
+    }
+
+    fn from_vars<F: Fn(&str) -> Option<PathBuf>>(var: F) -> Option<Location> {
+        if let Some(db) = var("TIMEWARRIORDB").filter(|db| !db.as_os_str().is_empty()) {
+            return Some(Location::in_dir(db));
+        }
+
+        let home = var("HOME")?;
+        let legacy = home.join(".timewarrior");
+        if legacy.is_dir() {
+            return Some(Location::in_dir(legacy));
+        }
+
+        let config_home = var("XDG_CONFIG_HOME").unwrap_or_else(|| home.join(".config"));
+        let data_home = var("XDG_DATA_HOME").unwrap_or_else(|| home.join(".local/share"));
+        Some(Location {
+            config_dir: config_home.join("timewarrior"),
+            data_dir: data_home.join("timewarrior").join("data"),
+        })
+    }
+
+    /// A database keeping its configuration in `dir` and its data in `dir/data`
+    pub fn in_dir<P: Into<PathBuf>>(dir: P) -> Location {
+        let dir = dir.into();
+        Location {
+            data_dir: dir.join("data"),
+            config_dir: dir,
+        }
+    }
+
+    /// `timewarrior.cfg` in the config directory
+    pub fn config_file(&self) -> PathBuf {
+        self.config_dir.join("timewarrior.cfg")
+    }
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// No database location could be derived from the environment
+    NotFound,
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The interval to change or delete is not in the data files
+    IntervalNotFound(TimeWarriorLine),
+    /// Another process held the lock of a file for too long
+    Locked {
+        path: PathBuf,
+        timeout: StdDuration,
+    },
+    /// Only interval changes can be undone, not changes of this kind
+    UnsupportedUndo(String),
+    /// `tags.data` is not valid
+    TagsData {
+        path: PathBuf,
+        error: TagsDataError,
+    },
+    /// `undo.data` is not a valid journal
+    Journal {
+        path: PathBuf,
+        error: Box<JournalError>,
+    },
+    /// `timewarrior.cfg` or a file it imports is not valid
+    Config {
+        path: PathBuf,
+        error: ConfigError,
+    },
+    /// A data file changed between planning and applying a repair
+    RepairOutdated {
+        path: PathBuf,
+    },
+}
+
+impl DatabaseError {
+    pub(crate) fn io<P: Into<PathBuf>>(path: P) -> impl FnOnce(io::Error) -> DatabaseError {
+        let path = path.into();
+        move |source| DatabaseError::Io { path, source }
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DatabaseError::NotFound => write!(f, "could not locate the timewarrior database"),
+            DatabaseError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            DatabaseError::IntervalNotFound(interval) => {
+                write!(f, "interval not found in the database: {}", interval)
+            }
+            DatabaseError::Locked { path, timeout } => write!(
+                f,
+                "could not acquire {} within {:?}, is another process writing?",
+                path.display(),
+                timeout
+            ),
+            DatabaseError::UnsupportedUndo(kind) => {
+                write!(f, "can not undo a change of type {:?}", kind)
+            }
+            DatabaseError::TagsData { path, error } => write!(f, "{}: {}", path.display(), error),
+            DatabaseError::Journal { path, error } => write!(f, "{}: {}", path.display(), error),
+            DatabaseError::Config { path, error } => write!(f, "{}: {}", path.display(), error),
+            DatabaseError::RepairOutdated { path } => {
+                write!(f, "{} changed since the repair was planned", path.display())
+            }
+        }
+    }
+}
+
+impl Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DatabaseError::Io { source, .. } => Some(source),
+            DatabaseError::TagsData { error, .. } => Some(error),
+            DatabaseError::Journal { error, .. } => Some(error.as_ref()),
+            DatabaseError::Config { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A line of a data file that could not be parsed, or that lenient parsing
+/// only understood by recovering from a problem
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineError {
+    pub path: PathBuf,
+    /// 1-based, like editors count lines
+    pub line_number: usize,
+    pub error: TimeWarriorLineError,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.path.display(),
+            self.line_number,
+            self.error
+        )
+    }
+}
+
+impl Error for LineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A monthly `YYYY-MM.data` file
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct DataFile {
+    pub year: i32,
+    pub month: u32,
+    pub path: PathBuf,
+}
+
+impl DataFile {
+    pub(crate) fn from_path(path: PathBuf) -> Option<DataFile> {
+        let name = path.file_name()?.to_str()?;
+        let stem = name.strip_suffix(".data")?;
+        let bytes = stem.as_bytes();
+        if bytes.len() != 7 || bytes[4] != b'-' {
+            return None;
+        }
+        if !bytes[..4].iter().chain(&bytes[5..]).all(u8::is_ascii_digit) {
+            return None;
+        }
+
+        let year = stem[..4].parse().ok()?;
+        let month = stem[5..].parse().ok()?;
+        if !(1..=12).contains(&month) {
+            return None;
+        }
+        Some(DataFile { year, month, path })
+    }
+
+    pub(crate) fn month(&self) -> (i32, u32) {
+        (self.year, self.month)
+    }
+}
+
+/// The monthly file timewarrior stores an interval starting at `from` in
+pub(crate) fn month_file(data_dir: &Path, from: DateTime<Utc>) -> PathBuf {
+    data_dir.join(format!("{:04}-{:02}.data", from.year(), from.month()))
+}
+
+/// Lists the monthly data files of `data_dir` in chronological order
+pub(crate) fn list_data_files(data_dir: &Path) -> Result<Vec<DataFile>, DatabaseError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(data_dir).map_err(DatabaseError::io(data_dir))? {
+        let entry = entry.map_err(DatabaseError::io(data_dir))?;
+        if let Some(file) = DataFile::from_path(entry.path()) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Older timewarrior versions copied an interval into every later monthly
+/// file it reaches into, this tells these copies from the first one read
+#[derive(Default)]
+pub(crate) struct LegacyCopies {
+    /// the intervals read so far that reach beyond their start month
+    seen: HashSet<TimeWarriorLine>,
+}
+
+impl LegacyCopies {
+    /// Whether `interval`, read from the file of `month`, repeats one read
+    /// before in a later month it reaches into
+    pub(crate) fn is_copy(&mut self, month: (i32, u32), interval: &TimeWarriorLine) -> bool {
+        let month_of = |date: DateTime<Utc>| (date.year(), date.month());
+        let start = month_of(interval.from());
+        let end = interval.until().map_or(start, month_of);
+        // only intervals leaving their start month can have copies
+        if end == start {
+            return false;
+        }
+        let first = self.seen.insert(interval.clone());
+        !first && start < month && month <= end
+    }
+}
+
+/// The intervals of all monthly data files of a timewarrior database
+#[derive(Debug, Clone)]
+pub struct Database {
+    data_dir: PathBuf,
+    intervals: Vec<TimeWarriorLine>,
+    errors: Vec<LineError>,
+    warnings: Vec<LineError>,
+    lock_timeout: StdDuration,
+    journal_size: Option<u64>,
+}
+
+impl Database {
+    /// Loads the database found by `Location::from_env`, keeping as many
+    /// transactions in `undo.data` as its `journal.size` setting says
+    pub fn open_default() -> Result<Database, DatabaseError> {
+        let location = Location::from_env().ok_or(DatabaseError::NotFound)?;
+        let path = location.config_file();
+        let journal_size = Config::load(&path)?
+            .journal_size()
+            .map_err(|error| DatabaseError::Config { path, error })?;
+
+        let mut database = Database::open(location.data_dir)?;
+        database.set_journal_size(journal_size);
+        Ok(database)
+    }
+
+    /// Loads all `YYYY-MM.data` files in `data_dir` with strict parsing
+    pub fn open<P: Into<PathBuf>>(data_dir: P) -> Result<Database, DatabaseError> {
+        Database::open_with(data_dir, ParseOptions::strict())
+    }
+
+    /// Loads all `YYYY-MM.data` files in `data_dir` in chronological order,
+    /// lines that can not be parsed end up in `errors` and problems lenient
+    /// parsing recovered from in `warnings`
+    pub fn open_with<P: Into<PathBuf>>(
+        data_dir: P,
+        options: ParseOptions,
+    ) -> Result<Database, DatabaseError> {
+        let mut database = Database {
+            data_dir: data_dir.into(),
+            intervals: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            journal_size: None,
+        };
+
+        let mut copies = LegacyCopies::default();
+        for file in list_data_files(&database.data_dir)? {
+            let intervals = read_file(
+                &file.path,
+                options,
+                &mut database.errors,
+                &mut database.warnings,
+            )?;
+            database.intervals.extend(
+                intervals
+                    .into_iter()
+                    .filter(|interval| !copies.is_copy(file.month(), interval)),
+            );
+        }
+        Ok(database)
+    }
+
+    /// Loads the intervals overlapping `[from, until)` with strict parsing
+    pub fn open_range<P: Into<PathBuf>>(
+        data_dir: P,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Database, DatabaseError> {
+        Database::open_range_with(data_dir, from, until, ParseOptions::strict())
+    }
+
+    /// Loads the intervals overlapping `[from, until)`, only reading the
+    /// monthly files that can contain them
+    ///
+    /// Timewarrior stores an interval in the file of the month it started in,
+    /// so an interval reaching into the range can live in an earlier file.
+    /// As intervals never overlap, only the last earlier file that contains
+    /// any interval has to be read to find it.
+    pub fn open_range_with<P: Into<PathBuf>>(
+        data_dir: P,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+        options: ParseOptions,
+    ) -> Result<Database, DatabaseError> {
+        let mut database = Database {
+            data_dir: data_dir.into(),
+            intervals: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            journal_size: None,
+        };
+        if until <= from {
+            return Ok(database);
+        }
+
+        let first = (from.year(), from.month());
+        let last_instant = until - Duration::nanoseconds(1);
+        let last = (last_instant.year(), last_instant.month());
+        let overlaps = |interval: &TimeWarriorLine| {
+            interval.from() < until && interval.until().is_none_or(|end| end > from)
+        };
+
+        let files = list_data_files(&database.data_dir)?;
+        let (earlier, later): (Vec<DataFile>, Vec<DataFile>) = files
+            .into_iter()
+            .partition(|file| (file.year, file.month) < first);
+
+        let mut copies = LegacyCopies::default();
+        let mut read = |file: &DataFile, database: &mut Database| {
+            let intervals = read_file(
+                &file.path,
+                options,
+                &mut database.errors,
+                &mut database.warnings,
+            )?;
+            let found_any = !intervals.is_empty();
+            database.intervals.extend(
+                intervals.into_iter().filter(|interval| {
+                    overlaps(interval) && !copies.is_copy(file.month(), interval)
+                }),
+            );
+            Ok::<_, DatabaseError>(found_any)
+        };
+
+        for file in earlier.iter().rev() {
+            if read(file, &mut database)? {
+                break;
+            }
+        }
+        for file in later
+            .iter()
+            .take_while(|file| (file.year, file.month) <= last)
+        {
+            read(file, &mut database)?;
+        }
+        Ok(database)
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// The intervals in the order they appear in the data files
+    pub fn intervals(&self) -> std::slice::Iter<'_, TimeWarriorLine> {
+        self.intervals.iter()
+    }
+
+    /// A copy of the intervals indexed for range and tag queries
+    pub fn index(&self) -> IntervalIndex {
+        self.intervals.iter().cloned().collect()
+    }
+
+    /// The lines that could not be parsed
+    pub fn errors(&self) -> &[LineError] {
+        &self.errors
+    }
+
+    /// The problems lenient parsing recovered from, one for each fix it made
+    /// to a line
+    pub fn warnings(&self) -> &[LineError] {
+        &self.warnings
+    }
+
+    /// How long changes wait for other processes to release the locks of the
+    /// files they rewrite, see `FileLock`
+    pub fn set_lock_timeout(&mut self, timeout: StdDuration) {
+        self.lock_timeout = timeout;
+    }
+
+    /// How many transactions `undo.data` keeps, dropping the oldest ones,
+    /// see `Config::journal_size`
+    ///
+    /// `None` keeps all of them, the default. Zero records no changes.
+    pub fn set_journal_size(&mut self, size: Option<u64>) {
+        self.journal_size = size;
+    }
+
+    /// Adds an interval to the file of the month it starts in
+    pub fn add(&mut self, interval: TimeWarriorLine) -> Result<(), DatabaseError> {
+        self.apply(None, Some(interval))
+    }
+
+    /// Replaces `before` with `after`, moving it to another file if the
+    /// start month changed
+    pub fn modify(
+        &mut self,
+        before: &TimeWarriorLine,
+        after: TimeWarriorLine,
+    ) -> Result<(), DatabaseError> {
+        self.apply(Some(before), Some(after))
+    }
+
+    pub fn delete(&mut self, interval: &TimeWarriorLine) -> Result<(), DatabaseError> {
+        self.apply(Some(interval), None)
+    }
+
+    /// Reverts the last transaction of the undo journal, like `timew undo`
+    ///
+    /// Returns the reverted transaction, or `None` if the journal is empty.
+    pub fn undo(&mut self) -> Result<Option<Transaction>, DatabaseError> {
+        fs::create_dir_all(&self.data_dir).map_err(DatabaseError::io(&self.data_dir))?;
+        let path = self.journal_path();
+
+        // the files to lock depend on the transaction, which is read again
+        // once they are locked in case another process undid it meanwhile
+        let (mut locks, journal, transaction) = loop {
+            let transaction = match Journal::load(&path)?.pop() {
+                Some(transaction) => transaction,
+                None => return Ok(None),
+            };
+            let mut files = vec![path.clone()];
+            for action in &transaction.actions {
+                if let UndoAction::Interval { before, after } = action {
+                    files.extend(self.files_to_change(after.as_ref(), before.as_ref()));
+                }
+            }
+            let locks = FileLocks::acquire(files, self.lock_timeout)?;
+
+            let mut journal = Journal::load(&path)?;
+            if journal.pop().as_ref() == Some(&transaction) {
+                break (locks, journal, transaction);
+            }
+        };
+
+        // refuse before touching any file, a transaction is undone entirely
+        if let Some(UndoAction::Other { kind, .. }) = transaction
+            .actions
+            .iter()
+            .find(|action| matches!(action, UndoAction::Other { .. }))
+        {
+            return Err(DatabaseError::UnsupportedUndo(kind.clone()));
+        }
+        let reverted = transaction
+            .actions
+            .iter()
+            .rev()
+            .filter_map(|action| match action {
+                UndoAction::Interval { before, after } => Some((after.as_ref(), before.as_ref())),
+                UndoAction::Other { .. } => None,
+            });
+        let mut staged = Staged::default();
+        for (before, after) in reverted.clone() {
+            self.stage(&mut staged, &locks, before, after)?;
+        }
+        staged.write(&mut locks)?;
+        for (before, after) in reverted {
+            self.update_intervals(before, after.cloned());
+        }
+        locks.replace(&path, &journal.to_string())?;
+        Ok(Some(transaction))
+    }
+
+    /// `tags.data`, the usage counts of the tags
+    pub fn tags_path(&self) -> PathBuf {
+        self.data_dir.join("tags.data")
+    }
+
+    /// `undo.data`, the journal of changes `timew undo` reverts
+    pub fn journal_path(&self) -> PathBuf {
+        self.data_dir.join("undo.data")
+    }
+
+    /// Looks for problems in the data files, like unsorted lines or
+    /// overlapping intervals
+    ///
+    /// This reads the files again, so it also covers lines that did not
+    /// parse or that lie outside a range this database was opened with.
+    pub fn check(&self) -> Result<Vec<Finding>, DatabaseError> {
+        check::check(&self.data_dir)
+    }
+
+    /// The changes fixing what `check` finds, as far as that can be done
+    /// without guessing
+    ///
+    /// Lines are sorted and moved to the file of their start month,
+    /// duplicates dropped and lines only lenient parsing understands
+    /// rewritten. An interval overlapping a later one, or open while a later
+    /// one is too, ends where the later one starts. Intervals lying inside
+    /// a longer one and lines that do not parse at all are left alone.
+    pub fn plan_repair(&self) -> Result<Repair, DatabaseError> {
+        check::plan(&self.data_dir)
+    }
+
+    /// Applies a planned repair and reloads the intervals, keeping the
+    /// settings of this database
+    ///
+    /// Repairs are not recorded in `undo.data`.
+    pub fn repair(&mut self, repair: &Repair) -> Result<(), DatabaseError> {
+        repair.apply(self.lock_timeout)?;
+        let reloaded = Database::open(self.data_dir.clone())?;
+        self.intervals = reloaded.intervals;
+        self.errors = reloaded.errors;
+        self.warnings = reloaded.warnings;
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        before: Option<&TimeWarriorLine>,
+        after: Option<TimeWarriorLine>,
+    ) -> Result<(), DatabaseError> {
+        fs::create_dir_all(&self.data_dir).map_err(DatabaseError::io(&self.data_dir))?;
+        let mut files = self.files_to_change(before, after.as_ref());
+        files.push(self.journal_path());
+        let mut locks = FileLocks::acquire(files, self.lock_timeout)?;
+
+        let transaction = Transaction {
+            actions: vec![UndoAction::Interval {
+                before: before.cloned(),
+                after: after.clone(),
+            }],
+        };
+        let mut staged = Staged::default();
+        self.stage(&mut staged, &locks, before, after.as_ref())?;
+        staged.write(&mut locks)?;
+        self.update_intervals(before, after);
+        self.record(&mut locks, transaction)
+    }
+
+    /// The data files removing `before` and adding `after` rewrites, which
+    /// are the ones to lock
+    fn files_to_change(
+        &self,
+        before: Option<&TimeWarriorLine>,
+        after: Option<&TimeWarriorLine>,
+    ) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = before
+            .map(|before| spanned_files(&self.data_dir, before))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect();
+        files.extend(after.map(|after| month_file(&self.data_dir, after.from())));
+        files
+    }
+
+    /// Adds a transaction to `undo.data`, keeping at most `journal_size`
+    fn record(&self, locks: &mut FileLocks, transaction: Transaction) -> Result<(), DatabaseError> {
+        let path = self.journal_path();
+        match self.journal_size {
+            None => Journal::append(&path, &transaction),
+            Some(0) => Ok(()),
+            Some(size) => {
+                let mut journal = Journal::load(&path)?;
+                journal.push(transaction);
+                journal.keep_latest(size as usize);
+                locks.replace(&path, &journal.to_string())
+            }
+        }
+    }
+
+    /// Removes `before` from the staged files and adds `after`
+    fn stage(
+        &self,
+        staged: &mut Staged,
+        locks: &FileLocks,
+        before: Option<&TimeWarriorLine>,
+        after: Option<&TimeWarriorLine>,
+    ) -> Result<(), DatabaseError> {
+        if let Some(before) = before {
+            self.stage_removal(staged, locks, before)?;
+        }
+        if let Some(after) = after {
+            let path = month_file(&self.data_dir, after.from());
+            staged.lines(&path)?.push(after.to_string());
+            staged.changed.insert(path);
+        }
+        Ok(())
+    }
+
+    // Intervals live in the file of their start month, older timewarrior
+    // versions also copied them into every later month they reach into.
+    fn stage_removal(
+        &self,
+        staged: &mut Staged,
+        locks: &FileLocks,
+        interval: &TimeWarriorLine,
+    ) -> Result<(), DatabaseError> {
+        let mut found = false;
+
+        // files missing when they were locked can not hold the interval
+        for path in spanned_files(&self.data_dir, interval) {
+            if !locks.contains(&path) {
+                continue;
+            }
+            let lines = staged.lines(&path)?;
+            let count = lines.len();
+            lines.retain(|line| TimeWarriorLine::from_str(line).ok().as_ref() != Some(interval));
+            if lines.len() != count {
+                staged.changed.insert(path);
+                found = true;
+            }
+        }
+
+        if found {
+            Ok(())
+        } else {
+            Err(DatabaseError::IntervalNotFound(interval.clone()))
+        }
+    }
+
+    /// Replaces `before` with `after` in the loaded intervals
+    fn update_intervals(
+        &mut self,
+        before: Option<&TimeWarriorLine>,
+        after: Option<TimeWarriorLine>,
+    ) {
+        if let Some(before) = before {
+            self.intervals.retain(|interval| interval != before);
+        }
+        if let Some(after) = after {
+            let position = self
+                .intervals
+                .partition_point(|interval| interval.from() <= after.from());
+            self.intervals.insert(position, after);
+        }
+    }
+}
+
+/// The lines of the data files a change rewrites
+///
+/// All actions of a change are staged before any file is written, so a
+/// change failing halfway leaves the files alone.
+#[derive(Default)]
+struct Staged {
+    files: BTreeMap<PathBuf, Vec<String>>,
+    changed: BTreeSet<PathBuf>,
+}
+
+impl Staged {
+    /// The staged lines of `path`, read from the file the first time
+    fn lines(&mut self, path: &Path) -> Result<&mut Vec<String>, DatabaseError> {
+        match self.files.entry(path.to_owned()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(read_lines(path)?)),
+        }
+    }
+
+    fn write(mut self, locks: &mut FileLocks) -> Result<(), DatabaseError> {
+        for path in &self.changed {
+            let lines = self.files.remove(path).unwrap_or_default();
+            write_lines(locks, path, lines)?;
+        }
+        Ok(())
+    }
+}
+
+/// The monthly files from the start to the end month of `interval`
+fn spanned_files(data_dir: &Path, interval: &TimeWarriorLine) -> Vec<PathBuf> {
+    let mut month = interval.from();
+    let last = interval.until().unwrap_or(month);
+    let mut files = Vec::new();
+    while (month.year(), month.month()) <= (last.year(), last.month()) {
+        files.push(month_file(data_dir, month));
+        month = next_month(month);
+    }
+    files
+}
+
+fn next_month(date: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = match date.month() {
+        12 => (date.year() + 1, 1),
+        month => (date.year(), month + 1),
+    };
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0)
+        .single()
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// The non-empty lines of a data file, a missing file has no lines
+fn read_lines(path: &Path) -> Result<Vec<String>, DatabaseError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_owned)
+            .collect()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(DatabaseError::io(path)(error)),
+    }
+}
+
+/// Sorts the lines like timewarrior does and replaces the locked file
+/// atomically
+fn write_lines(
+    locks: &mut FileLocks,
+    path: &Path,
+    mut lines: Vec<String>,
+) -> Result<(), DatabaseError> {
+    lines.sort();
+
+    let mut content = String::new();
+    for line in lines {
+        content.push_str(&line);
+        content.push('\n');
+    }
+    locks.replace(path, &content)
+}
+
+pub(crate) fn read_file(
+    path: &Path,
+    options: ParseOptions,
+    errors: &mut Vec<LineError>,
+    warnings: &mut Vec<LineError>,
+) -> Result<Vec<TimeWarriorLine>, DatabaseError> {
+    let content = fs::read_to_string(path).map_err(DatabaseError::io(path))?;
+    Ok(parse_file(path, &content, options, errors, warnings))
+}
+
+/// Parses the `content` of the data file `path`
+pub(crate) fn parse_file(
+    path: &Path,
+    content: &str,
+    options: ParseOptions,
+    errors: &mut Vec<LineError>,
+    warnings: &mut Vec<LineError>,
+) -> Vec<TimeWarriorLine> {
+    let mut intervals = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let at_line = |error| LineError {
+            path: path.to_owned(),
+            line_number: index + 1,
+            error,
+        };
+        match TimeWarriorLine::parse_with(line, options) {
+            Ok(parsed) => {
+                warnings.extend(parsed.warnings.into_iter().map(at_line));
+                intervals.push(parsed.line);
+            }
+            Err(error) => errors.push(at_line(error)),
+        }
+    }
+    intervals
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::{interval, TempDir};
+    use crate::FileLock;
+
+    #[test]
+    fn loads_monthly_files_in_chronological_order() {
+        let dir = TempDir::new("database-order");
+        dir.write(
+            "data/2020-10.data",
+            "inc 20201001T080000Z - 20201001T090000Z # october\n",
+        );
+        dir.write(
+            "data/2019-12.data",
+            "inc 20191201T080000Z - 20191201T090000Z # december\n",
+        );
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # february\n\ninc 20200202T080000Z # open\n",
+        );
+        dir.write("data/tags.data", "{}");
+        dir.write("data/2020-13.data", "inc broken");
+        dir.write("data/notes.data", "inc broken");
+
+        let database = Database::open(dir.path().join("data")).unwrap();
+
+        let tags: Vec<&str> = database
+            .intervals()
+            .map(|interval| interval.tags()[0].as_str())
+            .collect();
+        assert_eq!(tags, vec!["december", "february", "open", "october"]);
+        assert_eq!(database.errors(), &[]);
+    }
+
+    #[test]
+    fn broken_lines_are_reported_with_file_and_line() {
+        let dir = TempDir::new("database-errors");
+        let path = dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # fine\ninc 20200202T080000Z - broken\n",
+        );
+
+        let database = Database::open(dir.path().join("data")).unwrap();
+
+        assert_eq!(database.intervals().count(), 1);
+        assert_eq!(
+            database.errors(),
+            &[LineError {
+                path: path.clone(),
+                line_number: 2,
+                error: TimeWarriorLineError::BadEnd {
+                    offset: 23,
+                    token: "broken".to_owned(),
+                },
+            }]
+        );
+        assert_eq!(
+            database.errors()[0].to_string(),
+            format!(
+                "{}:2: bad end timestamp \"broken\" at byte 23",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn lenient_fixes_are_reported_as_warnings() {
+        let dir = TempDir::new("database-warnings");
+        let path = dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # fine\n\
+             inc 20200202T080000Z - 20200202T090000Z # \"unterminated\n",
+        );
+
+        let strict = Database::open(dir.path().join("data")).unwrap();
+        assert_eq!(strict.intervals().count(), 1);
+        assert_eq!(strict.errors().len(), 1);
+        assert_eq!(strict.warnings(), &[]);
+
+        let lenient =
+            Database::open_with(dir.path().join("data"), ParseOptions::lenient()).unwrap();
+        assert_eq!(lenient.intervals().count(), 2);
+        assert_eq!(lenient.errors(), &[]);
+        assert_eq!(
+            lenient.warnings(),
+            &[LineError {
+                path,
+                line_number: 2,
+                error: TimeWarriorLineError::UnterminatedQuote { offset: 42 },
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_data_dir_is_an_io_error() {
+        let dir = TempDir::new("database-missing");
+
+        let result = Database::open(dir.path().join("data"));
+
+        assert_eq!(
+            matches!(result, Err(DatabaseError::Io { .. })),
+            true,
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn location_prefers_timewarriordb() {
+        let location = Location::from_vars(|name| match name {
+            "TIMEWARRIORDB" => Some(PathBuf::from("/db")),
+            "HOME" => Some(PathBuf::from("/home/user")),
+            _ => None,
+        });
+
+        assert_eq!(location, Some(Location::in_dir("/db")));
+        assert_eq!(location.unwrap().data_dir, PathBuf::from("/db/data"));
+    }
+
+    #[test]
+    fn location_uses_legacy_dir_if_it_exists() {
+        let home = TempDir::new("database-home");
+        std::fs::create_dir(home.path().join(".timewarrior")).unwrap();
+
+        let location = Location::from_vars(|name| match name {
+            "HOME" => Some(home.path().to_owned()),
+            _ => None,
+        });
+
+        assert_eq!(
+            location,
+            Some(Location::in_dir(home.path().join(".timewarrior")))
+        );
+    }
+
+    #[test]
+    fn location_falls_back_to_xdg_dirs() {
+        let home = TempDir::new("database-xdg");
+
+        let location = Location::from_vars(|name| match name {
+            "HOME" => Some(home.path().to_owned()),
+            "XDG_DATA_HOME" => Some(PathBuf::from("/xdg/data")),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(location.config_dir, home.path().join(".config/timewarrior"));
+        assert_eq!(
+            location.data_dir,
+            PathBuf::from("/xdg/data/timewarrior/data")
+        );
+        assert_eq!(Location::from_vars(|_| None), None);
+    }
+
+    fn tags(database: &Database) -> Vec<&str> {
+        database
+            .intervals()
+            .map(|interval| interval.tags()[0].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn range_only_reads_overlapping_months() {
+        let dir = TempDir::new("database-range");
+        dir.write("data/2010-01.data", "inc broken\n");
+        dir.write(
+            "data/2020-01.data",
+            "inc 20200131T080000Z - 20200131T090000Z # january\n",
+        );
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # first\n\
+             inc 20200210T080000Z - 20200210T090000Z # second\n\
+             inc 20200220T080000Z - 20200220T090000Z # third\n",
+        );
+        dir.write(
+            "data/2020-03.data",
+            "inc 20200301T080000Z - 20200301T090000Z # march\n",
+        );
+        dir.write("data/2020-04.data", "inc broken\n");
+
+        let from = Utc.with_ymd_and_hms(2020, 2, 5, 0, 0, 0).unwrap();
+        let until = Utc.with_ymd_and_hms(2020, 4, 1, 0, 0, 0).unwrap();
+        let database = Database::open_range(dir.path().join("data"), from, until).unwrap();
+
+        assert_eq!(tags(&database), vec!["second", "third", "march"]);
+        assert_eq!(database.errors(), &[]);
+    }
+
+    #[test]
+    fn range_finds_intervals_started_in_earlier_months() {
+        let dir = TempDir::new("database-spill");
+        dir.write("data/2019-10.data", "inc broken\n");
+        dir.write(
+            "data/2019-11.data",
+            "inc 20191101T080000Z - 20191101T090000Z # early\n\
+             inc 20191130T220000Z - 20200102T090000Z # long\n",
+        );
+        dir.write("data/2019-12.data", "");
+        dir.write("data/2020-01.data", "inc 20200110T080000Z # open\n");
+
+        let from = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let until = Utc.with_ymd_and_hms(2020, 1, 8, 0, 0, 0).unwrap();
+        let database = Database::open_range(dir.path().join("data"), from, until).unwrap();
+        assert_eq!(tags(&database), vec!["long"]);
+        assert_eq!(database.errors(), &[]);
+
+        let from = Utc.with_ymd_and_hms(2020, 3, 1, 0, 0, 0).unwrap();
+        let until = Utc.with_ymd_and_hms(2020, 3, 8, 0, 0, 0).unwrap();
+        let database = Database::open_range(dir.path().join("data"), from, until).unwrap();
+        assert_eq!(tags(&database), vec!["open"]);
+    }
+
+    #[test]
+    fn skips_intervals_copied_into_later_months() {
+        let dir = TempDir::new("database-copies");
+        let line = "inc 20200131T220000Z - 20200201T020000Z # night\n";
+        dir.write("data/2020-01.data", line);
+        dir.write(
+            "data/2020-02.data",
+            &format!("{}inc 20200202T080000Z # other\n", line),
+        );
+
+        let from = Utc.with_ymd_and_hms(2020, 1, 31, 0, 0, 0).unwrap();
+        let until = Utc.with_ymd_and_hms(2020, 2, 3, 0, 0, 0).unwrap();
+        let range = Database::open_range(dir.path().join("data"), from, until).unwrap();
+        assert_eq!(tags(&range), vec!["night", "other"]);
+
+        let all = Database::open(dir.path().join("data")).unwrap();
+        assert_eq!(tags(&all), vec!["night", "other"]);
+    }
+
+    #[test]
+    fn add_writes_sorted_month_file() {
+        let dir = TempDir::new("database-add");
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # first\n\
+             inc 20200220T080000Z - 20200220T090000Z # third\n",
+        );
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        database
+            .add(interval("inc 20200210T080000Z - 20200210T090000Z # second"))
+            .unwrap();
+        database
+            .add(interval("inc 20200301T080000Z # \"new month\""))
+            .unwrap();
+
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200201T080000Z - 20200201T090000Z # first\n\
+             inc 20200210T080000Z - 20200210T090000Z # second\n\
+             inc 20200220T080000Z - 20200220T090000Z # third\n"
+        );
+        assert_eq!(
+            dir.read("data/2020-03.data"),
+            "inc 20200301T080000Z # \"new month\"\n"
+        );
+        assert_eq!(
+            tags(&database),
+            vec!["first", "second", "third", "new month"]
+        );
+        assert_eq!(
+            tags(&Database::open(dir.path().join("data")).unwrap()),
+            vec!["first", "second", "third", "new month"]
+        );
+        assert_eq!(dir.path().join("data/.2020-02.data.tmp").exists(), false);
+    }
+
+    #[test]
+    fn interval_across_months_is_stored_in_start_month() {
+        let dir = TempDir::new("database-across");
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        database
+            .add(interval("inc 20200131T220000Z - 20200201T020000Z # night"))
+            .unwrap();
+
+        assert_eq!(
+            dir.read("data/2020-01.data"),
+            "inc 20200131T220000Z - 20200201T020000Z # night\n"
+        );
+        assert_eq!(dir.path().join("data/2020-02.data").exists(), false);
+
+        let from = Utc.with_ymd_and_hms(2020, 2, 1, 0, 0, 0).unwrap();
+        let until = Utc.with_ymd_and_hms(2020, 2, 2, 0, 0, 0).unwrap();
+        let database = Database::open_range(dir.path().join("data"), from, until).unwrap();
+        assert_eq!(tags(&database), vec!["night"]);
+    }
+
+    #[test]
+    fn modify_moves_interval_between_months() {
+        let dir = TempDir::new("database-modify");
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # first\n\
+             inc 20200220T080000Z - 20200220T090000Z # moved\n",
+        );
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        database
+            .modify(
+                &interval("inc 20200220T080000Z - 20200220T090000Z # moved"),
+                interval("inc 20200301T080000Z - 20200301T090000Z # moved"),
+            )
+            .unwrap();
+
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200201T080000Z - 20200201T090000Z # first\n"
+        );
+        assert_eq!(
+            dir.read("data/2020-03.data"),
+            "inc 20200301T080000Z - 20200301T090000Z # moved\n"
+        );
+        assert_eq!(tags(&database), vec!["first", "moved"]);
+    }
+
+    #[test]
+    fn delete_removes_interval_and_legacy_copies() {
+        let dir = TempDir::new("database-delete");
+        let line = "inc 20200131T220000Z - 20200201T020000Z # night\n";
+        dir.write("data/2020-01.data", line);
+        dir.write(
+            "data/2020-02.data",
+            &format!("{}inc 20200202T080000Z # other\n", line),
+        );
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        database
+            .delete(&interval("inc 20200131T220000Z - 20200201T020000Z # night"))
+            .unwrap();
+
+        assert_eq!(dir.read("data/2020-01.data"), "");
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200202T080000Z # other\n"
+        );
+        assert_eq!(tags(&database), vec!["other"]);
+    }
+
+    #[test]
+    fn deleting_unknown_interval_fails() {
+        let dir = TempDir::new("database-delete-unknown");
+        dir.write("data/2020-02.data", "inc 20200202T080000Z # other\n");
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        let result = database.delete(&interval("inc 20200202T090000Z # other"));
+
+        assert_eq!(
+            matches!(result, Err(DatabaseError::IntervalNotFound(_))),
+            true,
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200202T080000Z # other\n"
+        );
+    }
+
+    #[test]
+    fn changes_wait_for_the_lock() {
+        let dir = TempDir::new("database-locked");
+        dir.write("data/2020-02.data", "inc 20200202T080000Z # other\n");
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+        database.set_lock_timeout(StdDuration::from_millis(50));
+
+        let path = dir.path().join("data/2020-02.data");
+        let lock = FileLock::acquire(&path, StdDuration::from_millis(0)).unwrap();
+        let result = database.add(interval("inc 20200203T080000Z # new"));
+        assert_eq!(
+            matches!(result, Err(DatabaseError::Locked { .. })),
+            true,
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200202T080000Z # other\n"
+        );
+
+        drop(lock);
+        database
+            .add(interval("inc 20200203T080000Z # new"))
+            .unwrap();
+        FileLock::acquire(&path, StdDuration::from_millis(0)).unwrap();
+    }
+
+    #[test]
+    fn changes_are_recorded_in_the_journal() {
+        let dir = TempDir::new("database-journal");
+        dir.write("data/2020-02.data", "inc 20200202T080000Z # other\n");
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        database
+            .add(interval("inc 20200201T080000Z - 20200201T090000Z # new"))
+            .unwrap();
+        database
+            .modify(
+                &interval("inc 20200202T080000Z # other"),
+                interval("inc 20200202T080000Z - 20200202T100000Z # other"),
+            )
+            .unwrap();
+
+        assert_eq!(
+            dir.read("data/undo.data"),
+            r#"txn:
+  type: interval
+  before: 
+  after: {"start":"20200201T080000Z","end":"20200201T090000Z","tags":["new"]}
+txn:
+  type: interval
+  before: {"start":"20200202T080000Z","tags":["other"]}
+  after: {"start":"20200202T080000Z","end":"20200202T100000Z","tags":["other"]}
+"#
+        );
+    }
+
+    #[test]
+    fn journal_keeps_the_configured_number_of_transactions() {
+        let dir = TempDir::new("database-journal-size");
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+        database.set_journal_size(Some(2));
+
+        for hour in 8..11 {
+            let line = format!("inc 20200201T{:02}0000Z - 20200201T{:02}3000Z", hour, hour);
+            database.add(interval(&line)).unwrap();
+        }
+
+        let journal = Journal::load(&database.journal_path()).unwrap();
+        assert_eq!(
+            journal
+                .transactions()
+                .iter()
+                .map(|transaction| transaction.actions.clone())
+                .collect::<Vec<_>>(),
+            database
+                .intervals()
+                .skip(1)
+                .map(|interval| vec![UndoAction::Interval {
+                    before: None,
+                    after: Some(interval.clone()),
+                }])
+                .collect::<Vec<_>>()
+        );
+
+        database.set_journal_size(Some(0));
+        database
+            .add(interval("inc 20200201T120000Z - 20200201T130000Z"))
+            .unwrap();
+        assert_eq!(
+            Journal::load(&database.journal_path())
+                .unwrap()
+                .transactions()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn undo_reverts_changes_from_the_journal() {
+        let dir = TempDir::new("database-undo");
+        let original = "inc 20200201T080000Z - 20200201T090000Z # first\n\
+                        inc 20200202T080000Z # other\n";
+        dir.write("data/2020-02.data", original);
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        database
+            .add(interval("inc 20200301T080000Z - 20200301T090000Z # new"))
+            .unwrap();
+        database
+            .modify(
+                &interval("inc 20200202T080000Z # other"),
+                interval("inc 20200302T080000Z # other"),
+            )
+            .unwrap();
+        database
+            .delete(&interval("inc 20200201T080000Z - 20200201T090000Z # first"))
+            .unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(database.undo().unwrap().is_some(), true);
+        }
+        assert_eq!(database.undo().unwrap(), None);
+
+        assert_eq!(dir.read("data/2020-02.data"), original);
+        assert_eq!(dir.read("data/2020-03.data"), "");
+        assert_eq!(dir.read("data/undo.data"), "");
+        assert_eq!(tags(&database), vec!["first", "other"]);
+    }
+
+    #[test]
+    fn undo_refuses_other_kinds_of_changes() {
+        let dir = TempDir::new("database-undo-config");
+        let journal = r#"txn:
+  type: config
+  before: verbose = on
+  after: verbose = off
+  type: interval
+  before: 
+  after: {"start":"20200202T080000Z","tags":["other"]}
+"#;
+        dir.write("data/undo.data", journal);
+        dir.write("data/2020-02.data", "inc 20200202T080000Z # other\n");
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        let result = database.undo();
+
+        assert_eq!(
+            matches!(result, Err(DatabaseError::UnsupportedUndo(ref kind)) if kind == "config"),
+            true,
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200202T080000Z # other\n"
+        );
+        assert_eq!(dir.read("data/undo.data"), journal);
+        assert_eq!(tags(&database), vec!["other"]);
+    }
+
+    #[test]
+    fn undo_changes_nothing_if_part_of_a_transaction_is_missing() {
+        let dir = TempDir::new("database-undo-missing");
+        // undone last to first, the missing interval comes second
+        let journal = r#"txn:
+  type: interval
+  before: 
+  after: {"start":"20200203T080000Z","tags":["missing"]}
+  type: interval
+  before: 
+  after: {"start":"20200202T080000Z","tags":["other"]}
+"#;
+        dir.write("data/undo.data", journal);
+        dir.write("data/2020-02.data", "inc 20200202T080000Z # other\n");
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+
+        let result = database.undo();
+
+        assert_eq!(
+            matches!(result, Err(DatabaseError::IntervalNotFound(ref interval)) if interval.tags() == ["missing"]),
+            true,
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            dir.read("data/2020-02.data"),
+            "inc 20200202T080000Z # other\n"
+        );
+        assert_eq!(dir.read("data/undo.data"), journal);
+        assert_eq!(tags(&database), vec!["other"]);
+    }
+}
@@ -0,0 +1,551 @@
+use crate::holidays::parse_date;
+use crate::{Config, ConfigError, HolidayCalendar, TimeWarriorLine};
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+const DAY: u32 = 24 * 60 * 60;
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("monday", Weekday::Mon),
+    ("tuesday", Weekday::Tue),
+    ("wednesday", Weekday::Wed),
+    ("thursday", Weekday::Thu),
+    ("friday", Weekday::Fri),
+    ("saturday", Weekday::Sat),
+    ("sunday", Weekday::Sun),
+];
+
+/// The excluded times of a single day, like `<8:00 12:00-12:45 >17:00`
+///
+/// `<8:00` excludes the time before 8:00, `>17:00` the time after 17:00 and
+/// `12:00-12:45` the time in between. `24:00` is the end of the day.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DayExclusions {
+    /// sorted, disjoint ranges of seconds since midnight
+    ranges: Vec<Range<u32>>,
+}
+
+impl DayExclusions {
+    /// The whole day
+    pub fn all_day() -> DayExclusions {
+        DayExclusions {
+            ranges: std::iter::once(0..DAY).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    fn from_ranges(mut ranges: Vec<Range<u32>>) -> DayExclusions {
+        ranges.retain(|range| !range.is_empty());
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u32>> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        DayExclusions { ranges: merged }
+    }
+}
+
+/// A word of day exclusions that is not `<time`, `>time` or `time-time`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidExclusion(pub String);
+
+impl fmt::Display for InvalidExclusion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid exclusion {:?}, expected times like <8:00 12:00-12:45 >17:00",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidExclusion {}
+
+impl FromStr for DayExclusions {
+    type Err = InvalidExclusion;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for word in value.split_whitespace() {
+            let range = if let Some(time) = word.strip_prefix('<') {
+                parse_time(time).map(|end| 0..end)
+            } else if let Some(time) = word.strip_prefix('>') {
+                parse_time(time).map(|start| start..DAY)
+            } else {
+                word.split_once('-')
+                    .and_then(|(start, end)| Some(parse_time(start)?..parse_time(end)?))
+                    .filter(|range| range.start <= range.end)
+            };
+            ranges.push(range.ok_or_else(|| InvalidExclusion(word.to_owned()))?);
+        }
+        Ok(DayExclusions::from_ranges(ranges))
+    }
+}
+
+impl fmt::Display for DayExclusions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, range) in self.ranges.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            match (range.start, range.end) {
+                (0, DAY) => write!(f, "<{}", format_time(DAY))?,
+                (0, end) => write!(f, "<{}", format_time(end))?,
+                (start, DAY) => write!(f, ">{}", format_time(start))?,
+                (start, end) => write!(f, "{}-{}", format_time(start), format_time(end))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `H:MM` or `H:MM:SS` as seconds since midnight, up to `24:00`
+fn parse_time(time: &str) -> Option<u32> {
+    let parts: Vec<&str> = time.split(':').collect();
+    let valid = |part: &&str| {
+        !part.is_empty() && part.len() <= 2 && part.bytes().all(|b| b.is_ascii_digit())
+    };
+    if !(2..=3).contains(&parts.len()) || !parts.iter().all(valid) {
+        return None;
+    }
+
+    let number = |index: usize| parts.get(index).map_or(Some(0), |part| part.parse().ok());
+    let (hours, minutes, seconds) = (number(0)?, number(1)?, number(2)?);
+    let total = hours * 3600 + minutes * 60 + seconds;
+    if minutes >= 60 || seconds >= 60 || total > DAY {
+        return None;
+    }
+    Some(total)
+}
+
+fn format_time(seconds: u32) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if seconds == 0 {
+        format!("{}:{:02}", hours, minutes)
+    } else {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    }
+}
+
+/// What `exclusions.days.<date>` says about a date
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DayRule {
+    /// `on`, a working day without the exclusions of its weekday, even on a
+    /// holiday or a weekday that is usually off
+    Working,
+    /// `off`, a day off
+    Off,
+    /// Exclusions replacing those of the weekday
+    Times(DayExclusions),
+}
+
+/// A stretch of local time that is either excluded or available for work
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeBlock<Tz: TimeZone> {
+    pub start: DateTime<Tz>,
+    pub end: DateTime<Tz>,
+    pub excluded: bool,
+}
+
+/// The non-working time configured through `exclusions.*`, together with
+/// the holidays which are days off unless marked `on`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Exclusions {
+    /// indexed by days since Monday
+    weekdays: [DayExclusions; 7],
+    days: BTreeMap<NaiveDate, DayRule>,
+    holidays: HolidayCalendar,
+}
+
+impl Exclusions {
+    /// Reads `exclusions.<weekday>`, `exclusions.days.<YYYY_MM_DD>` and the
+    /// holidays
+    pub fn from_config(config: &Config) -> Result<Exclusions, ConfigError> {
+        let mut exclusions = Exclusions {
+            holidays: HolidayCalendar::from_config(config)?,
+            ..Exclusions::default()
+        };
+        for (name, value) in config.section("exclusions") {
+            let key = || format!("exclusions.{}", name);
+            let invalid = |expected| ConfigError::InvalidValue {
+                key: key(),
+                value: value.to_owned(),
+                expected,
+            };
+
+            if let Some(date) = name.strip_prefix("days.") {
+                let date = parse_date(date).ok_or_else(|| ConfigError::InvalidKey(key()))?;
+                let rule = match value {
+                    "on" => DayRule::Working,
+                    "off" => DayRule::Off,
+                    times => DayRule::Times(
+                        times
+                            .parse()
+                            .map_err(|_| invalid("on, off or times like <8:00 >17:00"))?,
+                    ),
+                };
+                exclusions.days.insert(date, rule);
+            } else {
+                let weekday = WEEKDAYS
+                    .iter()
+                    .find(|(day, _)| *day == name)
+                    .map(|(_, weekday)| *weekday)
+                    .ok_or_else(|| ConfigError::InvalidKey(key()))?;
+                exclusions.set_weekday(
+                    weekday,
+                    value
+                        .parse()
+                        .map_err(|_| invalid("times like <8:00 12:00-12:45 >17:00"))?,
+                );
+            }
+        }
+        Ok(exclusions)
+    }
+
+    pub fn weekday(&self, weekday: Weekday) -> &DayExclusions {
+        &self.weekdays[weekday.num_days_from_monday() as usize]
+    }
+
+    pub fn set_weekday(&mut self, weekday: Weekday, exclusions: DayExclusions) {
+        self.weekdays[weekday.num_days_from_monday() as usize] = exclusions;
+    }
+
+    pub fn day(&self, date: NaiveDate) -> Option<&DayRule> {
+        self.days.get(&date)
+    }
+
+    pub fn set_day(&mut self, date: NaiveDate, rule: DayRule) {
+        self.days.insert(date, rule);
+    }
+
+    pub fn holidays(&self) -> &HolidayCalendar {
+        &self.holidays
+    }
+
+    pub fn set_holidays(&mut self, holidays: HolidayCalendar) {
+        self.holidays = holidays;
+    }
+
+    /// The exclusions in effect on `date`
+    pub fn on(&self, date: NaiveDate) -> DayExclusions {
+        match self.days.get(&date) {
+            Some(DayRule::Working) => DayExclusions::default(),
+            Some(DayRule::Off) => DayExclusions::all_day(),
+            Some(DayRule::Times(times)) => times.clone(),
+            None if self.holidays.is_holiday(date) => DayExclusions::all_day(),
+            None => self.weekday(date.weekday()).clone(),
+        }
+    }
+
+    /// Splits the local days `from` up to but excluding `until` into
+    /// alternating excluded and available blocks
+    ///
+    /// Blocks meeting at midnight are joined, so the evening of one day and
+    /// the morning of the next form one excluded block.
+    pub fn blocks<Tz: TimeZone>(
+        &self,
+        from: NaiveDate,
+        until: NaiveDate,
+        tz: &Tz,
+    ) -> Vec<TimeBlock<Tz>> {
+        // (start, end, excluded) in local time
+        let mut naive: Vec<(NaiveDateTime, NaiveDateTime, bool)> = Vec::new();
+        let mut push =
+            |start: NaiveDateTime, end: NaiveDateTime, excluded: bool| match naive.last_mut() {
+                Some(last) if last.2 == excluded && last.1 == start => last.1 = end,
+                _ => naive.push((start, end, excluded)),
+            };
+
+        let mut date = from;
+        while date < until {
+            let midnight = date.and_time(NaiveTime::MIN);
+            let at = |seconds: u32| midnight + Duration::seconds(i64::from(seconds));
+            let mut cursor = 0;
+            for range in &self.on(date).ranges {
+                if range.start > cursor {
+                    push(at(cursor), at(range.start), false);
+                }
+                push(at(range.start), at(range.end), true);
+                cursor = range.end;
+            }
+            if cursor < DAY {
+                push(at(cursor), at(DAY), false);
+            }
+            date = match date.succ_opt() {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        naive
+            .into_iter()
+            .map(|(start, end, excluded)| TimeBlock {
+                start: local(tz, start),
+                end: local(tz, end),
+                excluded,
+            })
+            .filter(|block| block.start < block.end)
+            .collect()
+    }
+
+    /// The available time of the local days `from` up to but excluding
+    /// `until` that no interval covers
+    ///
+    /// Open intervals count as tracked up to `until`.
+    pub fn untracked<'a, Tz, I>(
+        &self,
+        from: NaiveDate,
+        until: NaiveDate,
+        tz: &Tz,
+        intervals: I,
+    ) -> Vec<Range<DateTime<Tz>>>
+    where
+        Tz: TimeZone,
+        I: IntoIterator<Item = &'a TimeWarriorLine>,
+    {
+        let end_of_range = local(tz, until.and_time(NaiveTime::MIN)).with_timezone(&Utc);
+        let mut tracked: Vec<(DateTime<Utc>, DateTime<Utc>)> = intervals
+            .into_iter()
+            .map(|interval| (interval.from(), interval.until().unwrap_or(end_of_range)))
+            .filter(|(start, end)| start < end)
+            .collect();
+        tracked.sort();
+
+        let mut untracked = Vec::new();
+        for block in self.blocks(from, until, tz) {
+            if block.excluded {
+                continue;
+            }
+            let (start, end) = (
+                block.start.with_timezone(&Utc),
+                block.end.with_timezone(&Utc),
+            );
+            let mut cursor = start;
+            for &(tracked_start, tracked_end) in &tracked {
+                if tracked_start >= end {
+                    break;
+                }
+                if tracked_end <= cursor {
+                    continue;
+                }
+                if tracked_start > cursor {
+                    untracked.push(cursor..tracked_start);
+                }
+                cursor = cursor.max(tracked_end);
+            }
+            if cursor < end {
+                untracked.push(cursor..end);
+            }
+        }
+        untracked
+            .into_iter()
+            .map(|range| range.start.with_timezone(tz)..range.end.with_timezone(tz))
+            .collect()
+    }
+}
+
+/// `naive` in `tz`, moving times skipped by a clock change to the first
+/// valid time after them
+fn local<Tz: TimeZone>(tz: &Tz, naive: NaiveDateTime) -> DateTime<Tz> {
+    let mut naive = naive;
+    loop {
+        if let Some(time) = tz.from_local_datetime(&naive).earliest() {
+            return time;
+        }
+        naive += Duration::minutes(15);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d").unwrap()
+    }
+
+    fn at(text: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap())
+    }
+
+    fn exclusions() -> Exclusions {
+        let config: Config = "define exclusions:
+  monday = <8:00 12:00-12:45 >17:00
+  tuesday = <8:00 >17:00
+  saturday = <24:00
+  sunday = >0:00
+  days:
+    2020_06_02 = off
+    2020_06_06 = on
+    2020_06_08 = <10:00 >14:00:30
+"
+        .parse()
+        .unwrap();
+        Exclusions::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn parses_and_writes_day_exclusions() {
+        let day: DayExclusions = ">17:00 12:00-12:45 <8:00 11:30-12:15".parse().unwrap();
+
+        assert_eq!(day.to_string(), "<8:00 11:30-12:45 >17:00");
+        assert_eq!(
+            "<24:00".parse::<DayExclusions>(),
+            Ok(DayExclusions::all_day())
+        );
+        assert_eq!("".parse::<DayExclusions>(), Ok(DayExclusions::default()));
+        for invalid in [
+            "8:00",
+            "<25:00",
+            ">8:60",
+            "12:00-11:00",
+            "<8",
+            "<8:00:00:00",
+        ] {
+            assert_eq!(
+                format!("<8:00 {}", invalid).parse::<DayExclusions>(),
+                Err(InvalidExclusion(invalid.to_owned()))
+            );
+        }
+        assert_eq!(
+            "<8 >17:00"
+                .parse::<DayExclusions>()
+                .unwrap_err()
+                .to_string(),
+            "invalid exclusion \"<8\", expected times like <8:00 12:00-12:45 >17:00"
+        );
+    }
+
+    #[test]
+    fn reads_exclusions_from_config() {
+        let exclusions = exclusions();
+
+        assert_eq!(
+            exclusions.weekday(Weekday::Mon).to_string(),
+            "<8:00 12:00-12:45 >17:00"
+        );
+        assert_eq!(exclusions.weekday(Weekday::Wed).is_empty(), true);
+        assert_eq!(exclusions.on(date("2020-06-02")), DayExclusions::all_day());
+        assert_eq!(
+            exclusions.on(date("2020-06-06")),
+            DayExclusions::default(),
+            "a working saturday lifts the saturday exclusions"
+        );
+        assert_eq!(exclusions.on(date("2020-06-13")), DayExclusions::all_day());
+        assert_eq!(
+            exclusions.on(date("2020-06-08")).to_string(),
+            "<10:00 >14:00:30"
+        );
+
+        let config: Config = "exclusions.funday = <8:00\n".parse().unwrap();
+        assert_eq!(
+            Exclusions::from_config(&config),
+            Err(ConfigError::InvalidKey("exclusions.funday".to_owned()))
+        );
+        let config: Config = "exclusions.monday = after 8\n".parse().unwrap();
+        assert_eq!(
+            Exclusions::from_config(&config).is_err(),
+            true,
+            "times must be valid"
+        );
+    }
+
+    #[test]
+    fn holidays_are_days_off_unless_marked_on() {
+        let config: Config = "exclusions.wednesday = <8:00 >17:00
+exclusions.thursday = <8:00 >17:00
+exclusions.days.2020_06_11 = on
+holidays.de-DE.2020_06_11 = Fronleichnam
+holidays.de-DE.2020_06_10 = Made up
+"
+        .parse()
+        .unwrap();
+        let exclusions = Exclusions::from_config(&config).unwrap();
+
+        assert_eq!(exclusions.holidays().iter().count(), 2);
+        assert_eq!(exclusions.on(date("2020-06-10")), DayExclusions::all_day());
+        assert_eq!(exclusions.on(date("2020-06-11")).is_empty(), true);
+        assert_eq!(
+            exclusions
+                .untracked(date("2020-06-10"), date("2020-06-11"), &Utc, &[])
+                .len(),
+            0
+        );
+
+        let mut without_holidays = exclusions.clone();
+        without_holidays.set_holidays(HolidayCalendar::default());
+        assert_eq!(
+            without_holidays.on(date("2020-06-10")).to_string(),
+            "<8:00 >17:00"
+        );
+    }
+
+    #[test]
+    fn splits_days_into_blocks() {
+        let blocks = exclusions().blocks(date("2020-06-01"), date("2020-06-03"), &Utc);
+
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| (block.start.to_string(), block.excluded))
+                .collect::<Vec<_>>(),
+            vec![
+                ("2020-06-01 00:00:00 UTC".to_owned(), true),
+                ("2020-06-01 08:00:00 UTC".to_owned(), false),
+                ("2020-06-01 12:00:00 UTC".to_owned(), true),
+                ("2020-06-01 12:45:00 UTC".to_owned(), false),
+                // the evening continues into tuesday, a day off
+                ("2020-06-01 17:00:00 UTC".to_owned(), true),
+            ]
+        );
+        assert_eq!(blocks.last().unwrap().end, at("2020-06-03 00:00"));
+    }
+
+    #[test]
+    fn blocks_follow_the_time_zone() {
+        let tz = FixedOffset::east_opt(2 * 3600).unwrap();
+        let blocks = exclusions().blocks(date("2020-06-01"), date("2020-06-02"), &tz);
+
+        assert_eq!(blocks[1].start.with_timezone(&Utc), at("2020-06-01 06:00"));
+        assert_eq!(blocks[1].start.to_string(), "2020-06-01 08:00:00 +02:00");
+    }
+
+    #[test]
+    fn finds_untracked_working_time() {
+        let intervals: Vec<TimeWarriorLine> = [
+            "inc 20200601T070000Z - 20200601T090000Z # early",
+            "inc 20200601T093000Z - 20200601T123000Z # lunch overlap",
+            "inc 20200601T160000Z # still running",
+        ]
+        .iter()
+        .map(|line| line.parse().unwrap())
+        .collect();
+
+        let untracked =
+            exclusions().untracked(date("2020-06-01"), date("2020-06-02"), &Utc, &intervals);
+
+        assert_eq!(
+            untracked,
+            vec![
+                at("2020-06-01 09:00")..at("2020-06-01 09:30"),
+                at("2020-06-01 12:45")..at("2020-06-01 16:00"),
+            ]
+        );
+        let total = untracked.iter().fold(Duration::zero(), |total, range| {
+            total + (range.end - range.start)
+        });
+        assert_eq!(total, Duration::minutes(30 + 195));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{Config, ConfigError, DatabaseError};
+use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A public holiday from a `holidays.<locale>` definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holiday {
+    pub date: NaiveDate,
+    pub name: String,
+    /// like `en-US`
+    pub locale: String,
+}
+
+/// The holidays timewarrior knows about, usually from the holiday files
+/// imported into `timewarrior.cfg`:
+///
+/// ```text
+/// define holidays:
+///   de-DE:
+///     2020_01_01 = Neujahr
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HolidayCalendar {
+    holidays: BTreeMap<NaiveDate, Vec<Holiday>>,
+}
+
+impl HolidayCalendar {
+    /// Collects the `holidays.<locale>.<date>` settings of all locales
+    pub fn from_config(config: &Config) -> Result<HolidayCalendar, ConfigError> {
+        let mut calendar = HolidayCalendar::default();
+        for (name, value) in config.section("holidays") {
+            let date = name
+                .rsplit_once('.')
+                .and_then(|(locale, date)| Some((locale, parse_date(date)?)));
+            match date {
+                Some((locale, date)) => calendar.insert(Holiday {
+                    date,
+                    name: value.to_owned(),
+                    locale: locale.to_owned(),
+                }),
+                None => return Err(ConfigError::InvalidKey(format!("holidays.{}", name))),
+            }
+        }
+        Ok(calendar)
+    }
+
+    /// Reads a holiday file like `holidays.en-US` on its own
+    pub fn load(path: &Path) -> Result<HolidayCalendar, DatabaseError> {
+        let config = Config::load(path)?;
+        HolidayCalendar::from_config(&config).map_err(|error| DatabaseError::Config {
+            path: path.to_owned(),
+            error,
+        })
+    }
+
+    pub fn insert(&mut self, holiday: Holiday) {
+        self.holidays.entry(holiday.date).or_default().push(holiday);
+    }
+
+    pub fn is_holiday(&self, date: NaiveDate) -> bool {
+        self.holidays.contains_key(&date)
+    }
+
+    /// The holidays on `date`, one per locale that has one
+    pub fn on(&self, date: NaiveDate) -> &[Holiday] {
+        self.holidays.get(&date).map_or(&[], Vec::as_slice)
+    }
+
+    /// The holidays from `from` up to but excluding `until`, by date
+    pub fn between(&self, from: NaiveDate, until: NaiveDate) -> impl Iterator<Item = &Holiday> {
+        let range = if from < until {
+            self.holidays.range(from..until)
+        } else {
+            self.holidays.range(from..from)
+        };
+        range.flat_map(|(_, holidays)| holidays)
+    }
+
+    /// All holidays by date
+    pub fn iter(&self) -> impl Iterator<Item = &Holiday> {
+        self.holidays.values().flatten()
+    }
+}
+
+/// A date as written in config keys, `2020_01_01` or `2020-01-01`
+pub(crate) fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y_%m_%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .ok()
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const HOLIDAYS: &str = "# German holidays
+define holidays:
+  de-DE:
+    2020_01_01 = Neujahr
+    2020_04_10 = Karfreitag
+    2020_12_25 = 1. Weihnachtstag
+";
+
+    fn date(text: &str) -> NaiveDate {
+        parse_date(text).unwrap()
+    }
+
+    #[test]
+    fn looks_up_holidays_by_date_and_range() {
+        let config: Config = format!("{}holidays.en-US.2020_01_01 = New Year's Day\n", HOLIDAYS)
+            .parse()
+            .unwrap();
+        let calendar = HolidayCalendar::from_config(&config).unwrap();
+
+        assert_eq!(calendar.is_holiday(date("2020-04-10")), true);
+        assert_eq!(calendar.is_holiday(date("2020-04-11")), false);
+        assert_eq!(
+            calendar
+                .on(date("2020-01-01"))
+                .iter()
+                .map(|holiday| (holiday.locale.as_str(), holiday.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("de-DE", "Neujahr"), ("en-US", "New Year's Day")]
+        );
+        assert_eq!(
+            calendar
+                .between(date("2020-01-02"), date("2020-12-25"))
+                .map(|holiday| holiday.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Karfreitag"]
+        );
+        assert_eq!(
+            calendar
+                .between(date("2021-01-01"), date("2020-01-01"))
+                .count(),
+            0
+        );
+        assert_eq!(calendar.iter().count(), 4);
+    }
+
+    #[test]
+    fn rejects_keys_without_a_date() {
+        let config: Config = "holidays.de-DE.christmas = Weihnachten\n".parse().unwrap();
+
+        assert_eq!(
+            HolidayCalendar::from_config(&config),
+            Err(ConfigError::InvalidKey(
+                "holidays.de-DE.christmas".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn loads_holiday_files_imported_by_the_config() {
+        let dir = TempDir::new("holidays");
+        let file = dir.write("holidays/holidays.de-DE", HOLIDAYS);
+        let config = dir.write("timewarrior.cfg", "import holidays/holidays.de-DE\n");
+
+        let imported = HolidayCalendar::from_config(&Config::load(&config).unwrap()).unwrap();
+
+        assert_eq!(imported, HolidayCalendar::load(&file).unwrap());
+        assert_eq!(imported.on(date("2020_12_25"))[0].name, "1. Weihnachtstag");
+    }
+}
@@ -0,0 +1,311 @@
+use crate::TimeWarriorLine;
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::HashMap;
+use std::iter::FromIterator;
+
+/// Subtrees with at most this many levels are scanned instead of descended
+const SCAN_LEVELS: u32 = 3;
+
+/// Intervals indexed for time range and tag queries
+///
+/// The intervals are kept sorted by start and laid out as an implicit
+/// binary search tree, where every node also knows the latest end below it.
+/// Range queries take `O(log n + k)` for `k` matches. Open intervals are
+/// treated as running forever.
+#[derive(Debug, Clone, Default)]
+pub struct IntervalIndex {
+    intervals: Vec<TimeWarriorLine>,
+    /// latest end within the subtree of each node, `None` for open
+    max_end: Vec<Option<DateTime<Utc>>>,
+    /// level of the root node
+    root_level: u32,
+    /// positions of the intervals using each tag
+    tags: HashMap<String, Vec<usize>>,
+}
+
+impl IntervalIndex {
+    pub fn new<I>(intervals: I) -> IntervalIndex
+    where
+        I: IntoIterator<Item = TimeWarriorLine>,
+    {
+        let mut intervals: Vec<TimeWarriorLine> = intervals.into_iter().collect();
+        intervals.sort_by_key(TimeWarriorLine::from);
+
+        let mut tags: HashMap<String, Vec<usize>> = HashMap::new();
+        for (position, interval) in intervals.iter().enumerate() {
+            for tag in interval.tags() {
+                let positions = tags.entry(tag.clone()).or_default();
+                // an interval listing a tag twice is found once
+                if positions.last() != Some(&position) {
+                    positions.push(position);
+                }
+            }
+        }
+
+        let (max_end, root_level) = build_tree(&intervals);
+        IntervalIndex {
+            intervals,
+            max_end,
+            root_level,
+            tags,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// All intervals ordered by start
+    pub fn iter(&self) -> std::slice::Iter<'_, TimeWarriorLine> {
+        self.intervals.iter()
+    }
+
+    /// Intervals sharing at least an instant with `[from, until)`, ordered
+    /// by start
+    pub fn overlapping(&self, from: DateTime<Utc>, until: DateTime<Utc>) -> Vec<&TimeWarriorLine> {
+        let mut positions = Vec::new();
+        if from < until {
+            self.search(from, until, &mut positions);
+        }
+        positions.sort_unstable();
+        positions.into_iter().map(|i| &self.intervals[i]).collect()
+    }
+
+    /// Intervals running at `instant`, its start included and its end not
+    pub fn active_at(&self, instant: DateTime<Utc>) -> Vec<&TimeWarriorLine> {
+        self.overlapping(instant, instant + Duration::nanoseconds(1))
+    }
+
+    /// Intervals tagged `tag`, ordered by start
+    pub fn with_tag<'a>(&'a self, tag: &str) -> impl Iterator<Item = &'a TimeWarriorLine> + 'a {
+        self.tags
+            .get(tag)
+            .map(|positions| positions.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(move |&i| &self.intervals[i])
+    }
+
+    /// Collects the positions of intervals with `start < until` and
+    /// `end > from`, in no particular order
+    fn search(&self, from: DateTime<Utc>, until: DateTime<Utc>, found: &mut Vec<usize>) {
+        let n = self.intervals.len();
+        if n == 0 {
+            return;
+        }
+        let overlaps = |i: usize| {
+            self.intervals[i].from() < until && ends_after(self.intervals[i].until(), from)
+        };
+
+        // (node, level, left child done)
+        let mut stack = vec![((1usize << self.root_level) - 1, self.root_level, false)];
+        while let Some((node, level, left_done)) = stack.pop() {
+            if level <= SCAN_LEVELS {
+                let first = node >> level << level;
+                let end = (first + (1 << (level + 1)) - 1).min(n);
+                for i in first..end {
+                    if self.intervals[i].from() >= until {
+                        break;
+                    }
+                    if overlaps(i) {
+                        found.push(i);
+                    }
+                }
+            } else if !left_done {
+                stack.push((node, level, true));
+                // nodes past the end are missing but may have children
+                let left = node - (1 << (level - 1));
+                if left >= n || ends_after(self.max_end[left], from) {
+                    stack.push((left, level - 1, false));
+                }
+            } else if node < n && self.intervals[node].from() < until {
+                if overlaps(node) {
+                    found.push(node);
+                }
+                stack.push((node + (1 << (level - 1)), level - 1, false));
+            }
+        }
+    }
+}
+
+impl FromIterator<TimeWarriorLine> for IntervalIndex {
+    fn from_iter<I: IntoIterator<Item = TimeWarriorLine>>(intervals: I) -> Self {
+        IntervalIndex::new(intervals)
+    }
+}
+
+impl<'a> IntoIterator for &'a IntervalIndex {
+    type Item = &'a TimeWarriorLine;
+    type IntoIter = std::slice::Iter<'a, TimeWarriorLine>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Whether an interval ending at `end` is still running after `instant`
+fn ends_after(end: Option<DateTime<Utc>>, instant: DateTime<Utc>) -> bool {
+    end.is_none_or(|end| end > instant)
+}
+
+fn latest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        _ => None,
+    }
+}
+
+/// The latest end below every node and the level of the root
+///
+/// Node `i` sits on the level given by its trailing one bits, so leaves are
+/// the even positions. Nodes past the end of the slice are left out; the
+/// right child of a node then borrows the latest end of the last real node.
+fn build_tree(intervals: &[TimeWarriorLine]) -> (Vec<Option<DateTime<Utc>>>, u32) {
+    let n = intervals.len();
+    let mut max_end: Vec<Option<DateTime<Utc>>> =
+        intervals.iter().map(TimeWarriorLine::until).collect();
+    if n == 0 {
+        return (max_end, 0);
+    }
+
+    let mut last_node = (n - 1) & !1;
+    let mut last_end = max_end[last_node];
+    let mut level = 1;
+    while 1 << level <= n {
+        let half = 1 << (level - 1);
+        let mut node = (half << 1) - 1;
+        while node < n {
+            let left = max_end[node - half];
+            let right = if node + half < n {
+                max_end[node + half]
+            } else {
+                last_end
+            };
+            max_end[node] = latest(max_end[node], latest(left, right));
+            node += half << 2;
+        }
+        last_node = if last_node >> level & 1 == 1 {
+            last_node - half
+        } else {
+            last_node + half
+        };
+        if last_node < n {
+            last_end = latest(last_end, max_end[last_node]);
+        }
+        level += 1;
+    }
+    (max_end, level - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::interval;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        crate::parse_timestamp(timestamp).unwrap()
+    }
+
+    fn index() -> IntervalIndex {
+        vec![
+            "inc 20200203T080000Z # open",
+            "inc 20200201T080000Z - 20200201T090000Z # work",
+            "inc 20200201T090000Z - 20200201T120000Z # work meeting",
+            "inc 20200101T000000Z - 20200301T000000Z # long",
+            "inc 20200202T080000Z - 20200202T090000Z # lunch",
+        ]
+        .into_iter()
+        .map(interval)
+        .collect()
+    }
+
+    fn starts(intervals: Vec<&TimeWarriorLine>) -> Vec<String> {
+        intervals
+            .into_iter()
+            .map(|interval| crate::format_timestamp(&interval.from()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_overlapping_intervals() {
+        let index = index();
+
+        assert_eq!(
+            starts(index.overlapping(at("20200201T083000Z"), at("20200201T090000Z"))),
+            vec!["20200101T000000Z", "20200201T080000Z"]
+        );
+        assert_eq!(
+            starts(index.overlapping(at("20200301T000000Z"), at("20200401T000000Z"))),
+            vec!["20200203T080000Z"]
+        );
+        assert_eq!(
+            index.overlapping(at("20200201T090000Z"), at("20200201T090000Z")),
+            Vec::<&TimeWarriorLine>::new()
+        );
+    }
+
+    #[test]
+    fn finds_intervals_active_at_an_instant() {
+        let index = index();
+
+        assert_eq!(
+            starts(index.active_at(at("20200201T090000Z"))),
+            vec!["20200101T000000Z", "20200201T090000Z"]
+        );
+        assert_eq!(
+            starts(index.active_at(at("20210101T000000Z"))),
+            vec!["20200203T080000Z"]
+        );
+        assert_eq!(
+            index.active_at(at("20191231T235959Z")),
+            Vec::<&TimeWarriorLine>::new()
+        );
+    }
+
+    #[test]
+    fn finds_intervals_by_tag() {
+        let index = index();
+
+        assert_eq!(
+            starts(index.with_tag("work").collect()),
+            vec!["20200201T080000Z", "20200201T090000Z"]
+        );
+        assert_eq!(index.with_tag("missing").count(), 0);
+    }
+
+    #[test]
+    fn agrees_with_linear_scan() {
+        // sizes around powers of two leave nodes missing from the tree
+        for n in [0, 1, 2, 3, 15, 16, 17, 100, 257] {
+            let base = at("20200101T000000Z");
+            let index: IntervalIndex = (0..n)
+                .map(|i: i64| {
+                    let from = base + Duration::minutes(i * 37 % 1000);
+                    let until = from + Duration::minutes(i * 13 % 120 + 1);
+                    TimeWarriorLine::builder(from).until(until).build().unwrap()
+                })
+                .collect();
+
+            for minute in (0..1200).step_by(7) {
+                let from = base + Duration::minutes(minute);
+                let until = from + Duration::minutes(minute % 90 + 1);
+                let expected: Vec<&TimeWarriorLine> = index
+                    .iter()
+                    .filter(|i| i.from() < until && ends_after(i.until(), from))
+                    .collect();
+                assert_eq!(
+                    index.overlapping(from, until),
+                    expected,
+                    "n = {}, minute = {}",
+                    n,
+                    minute
+                );
+            }
+        }
+    }
+}
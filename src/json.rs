@@ -0,0 +1,348 @@
+//! Just enough JSON for the small files timewarrior keeps next to its data
+
+use std::fmt::Write;
+use std::str::CharIndices;
+
+/// How deep arrays and objects may nest, far more than any timewarrior file
+/// needs but few enough to keep the recursive parser off the stack limit
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// members in the order they were written
+    Object(Vec<(String, Value)>),
+}
+
+/// Where and why a JSON document could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+pub(crate) fn parse(input: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser {
+        input,
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != input.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+/// Writes `value` as a quoted JSON string
+pub(crate) fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    /// arrays and objects open at `pos`
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            offset: self.pos,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8, message: &'static str) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{' | b'[') => self.nested(),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn nested(&mut self) -> Result<Value, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = match self.peek() {
+            Some(b'{') => self.object(),
+            _ => self.array(),
+        };
+        self.depth -= 1;
+        value
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, ParseError> {
+        if self.input[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("expected a value"))
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.input[start..self.pos]
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| ParseError {
+                offset: start,
+                message: "invalid number",
+            })
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"', "expected a string")?;
+        let mut value = String::new();
+        let mut chars = self.input[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => {
+                            let error = ParseError {
+                                offset: self.pos + i,
+                                message: "invalid unicode escape",
+                            };
+                            let mut code = hex_digits(&mut chars).ok_or(error.clone())?;
+                            // characters beyond U+FFFF come as a pair of surrogates
+                            if (0xD800..0xDC00).contains(&code) {
+                                code = match (chars.next(), chars.next(), hex_digits(&mut chars)) {
+                                    (Some((_, '\\')), Some((_, 'u')), Some(low))
+                                        if (0xDC00..0xE000).contains(&low) =>
+                                    {
+                                        0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
+                                    }
+                                    _ => return Err(error),
+                                };
+                            }
+                            char::from_u32(code).ok_or(error)?
+                        }
+                        Some(c) => c,
+                        None => break,
+                    };
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect(b'[', "expected an array")?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.expect(b'{', "expected an object")?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(b':', "expected ':'")?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+/// The four hex digits of a `\\u` escape
+fn hex_digits(chars: &mut CharIndices) -> Option<u32> {
+    (0..4).try_fold(0, |code, _| {
+        let digit = chars.next()?.1.to_digit(16)?;
+        Some(code * 16 + digit)
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_documents() {
+        let value =
+            parse(r#" {"a": {"count": 3, "list": [1, -2.5e1, true, null]}, "b\"ä": "x\ny"} "#)
+                .unwrap();
+
+        assert_eq!(
+            value,
+            Value::Object(vec![
+                (
+                    "a".to_owned(),
+                    Value::Object(vec![
+                        ("count".to_owned(), Value::Number(3.0)),
+                        (
+                            "list".to_owned(),
+                            Value::Array(vec![
+                                Value::Number(1.0),
+                                Value::Number(-25.0),
+                                Value::Bool(true),
+                                Value::Null,
+                            ])
+                        ),
+                    ])
+                ),
+                ("b\"ä".to_owned(), Value::String("x\ny".to_owned())),
+            ])
+        );
+    }
+
+    #[test]
+    fn reports_error_offsets() {
+        assert_eq!(
+            parse(r#"{"a": 1,}"#),
+            Err(ParseError {
+                offset: 8,
+                message: "expected a string",
+            })
+        );
+        assert_eq!(parse(r#"{"a" 1}"#).unwrap_err().offset, 5);
+        assert_eq!(
+            parse(r#""open"#).unwrap_err().message,
+            "unterminated string"
+        );
+        assert_eq!(parse("{} x").unwrap_err().message, "trailing characters");
+    }
+
+    #[test]
+    fn decodes_surrogate_pairs() {
+        assert_eq!(
+            parse(r#""smile \uD83D\uDE00 \u00e4""#),
+            Ok(Value::String("smile \u{1F600} ä".to_owned()))
+        );
+
+        for broken in [
+            r#""\uD83D""#,
+            r#""\uD83Dx""#,
+            r#""\uD83D\u0041""#,
+            r#""\uDE00""#,
+        ] {
+            assert_eq!(
+                parse(broken).unwrap_err().message,
+                "invalid unicode escape",
+                "{}",
+                broken
+            );
+        }
+        assert_eq!(parse(r#""\u+041""#).unwrap_err().offset, 1);
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+
+        assert_eq!(parse(&nested(MAX_DEPTH)).is_ok(), true);
+        assert_eq!(
+            parse(&nested(MAX_DEPTH + 1)),
+            Err(ParseError {
+                offset: MAX_DEPTH,
+                message: "nested too deeply",
+            })
+        );
+        assert_eq!(
+            parse(&"{\"a\":".repeat(100_000)).unwrap_err().message,
+            "nested too deeply"
+        );
+    }
+
+    #[test]
+    fn quoted_strings_parse_back() {
+        let text = "tab\t \"quote\" back\\slash \u{1}";
+
+        assert_eq!(parse(&quote(text)), Ok(Value::String(text.to_owned())));
+    }
+}
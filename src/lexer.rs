@@ -0,0 +1,123 @@
+use std::borrow::Cow;
+
+/// A whitespace separated word of a data line, quotes can span whitespace
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Token<'a> {
+    /// byte offset of the token in the line
+    pub offset: usize,
+    /// the token as written, including quotes and escapes
+    pub raw: &'a str,
+    pub quoted: bool,
+    /// byte offset of a quote that is still open at the end of the line
+    pub unterminated_quote: Option<usize>,
+}
+
+impl<'a> Token<'a> {
+    /// true for the unquoted word `word`, a quoted `"#"` is a tag and not a separator
+    pub fn is(&self, word: &str) -> bool {
+        !self.quoted && self.raw == word
+    }
+
+    /// The token with quotes removed and escapes resolved, only copied if
+    /// there are quotes or escapes to remove
+    ///
+    /// Escapes follow timewarrior's lexer: `\b`, `\f`, `\n`, `\r`, `\t` and `\v`
+    /// are control characters, `\uXXXX` is a unicode code point and any other
+    /// escaped character stands for itself.
+    pub fn text(&self) -> Cow<'a, str> {
+        if !self.raw.contains(['"', '\\']) {
+            return Cow::Borrowed(self.raw);
+        }
+
+        let mut value = String::with_capacity(self.raw.len());
+        let mut chars = self.raw.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('v') => value.push('\u{b}'),
+                    Some('u') => {
+                        let hex: String = chars.clone().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(code_point)
+                                if hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+                            {
+                                value.push(code_point);
+                                chars.nth(3);
+                            }
+                            _ => value.push('u'),
+                        }
+                    }
+                    Some(next) => value.push(next),
+                    None => value.push(c),
+                },
+                '"' => (),
+                c => value.push(c),
+            }
+        }
+        Cow::Owned(value)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Lexer<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Lexer { line, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = &self.line[self.pos..];
+        let start = self.pos + (rest.len() - rest.trim_start().len());
+        if start == self.line.len() {
+            self.pos = start;
+            return None;
+        }
+
+        let mut quote_start = None;
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut end = self.line.len();
+        for (i, c) in self.line[start..].char_indices() {
+            if escaped {
+                escaped = false;
+                continue;
+            }
+            match c {
+                '\\' => escaped = true,
+                '"' => {
+                    quoted = true;
+                    quote_start = match quote_start {
+                        Some(_) => None,
+                        None => Some(start + i),
+                    };
+                }
+                c if c.is_whitespace() && quote_start.is_none() => {
+                    end = start + i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        self.pos = end;
+        Some(Token {
+            offset: start,
+            raw: &self.line[start..end],
+            quoted,
+            unterminated_quote: quote_start,
+        })
+    }
+}
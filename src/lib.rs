@@ -1,19 +1,110 @@
+mod check;
+mod config;
+mod config_file;
+mod database;
+mod exclusions;
+mod holidays;
+mod index;
+mod json;
+mod lexer;
+mod line_ref;
+mod lock;
+mod settings;
+mod tags;
+#[cfg(test)]
+mod test_util;
+mod timestamp;
+mod undo;
+#[cfg(feature = "watch")]
+mod watch;
+
+pub use check::{FileChange, Finding, Problem, Repair};
+pub use config::{Config, ConfigError};
+pub use config_file::ConfigFile;
+pub use database::{Database, DatabaseError, LineError, Location};
+pub use exclusions::{DayExclusions, DayRule, Exclusions, InvalidExclusion, TimeBlock};
+pub use holidays::{Holiday, HolidayCalendar};
+pub use index::IntervalIndex;
+pub use line_ref::{Tags, TimeWarriorLineRef};
+pub use lock::FileLock;
+pub use settings::{RangeHint, UnknownRangeHint};
+pub use tags::{TagDrift, TagInfo, TagsData, TagsDataError};
+pub use timestamp::{format_timestamp, parse_timestamp, TimestampOutOfRange};
+pub use undo::{Journal, JournalError, Transaction, UndoAction};
+#[cfg(feature = "watch")]
+pub use watch::{diff, WatchEvent, Watcher};
+
 use chrono::prelude::*;
+use lexer::{Lexer, Token};
+use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TimeWarriorLine {
-    tw_type: String,
+    tw_type: RecordKind,
     from: DateTime<Utc>,
-    until: DateTime<Utc>,
+    until: Option<DateTime<Utc>>,
     tags: Vec<String>,
-    active: bool,
+    annotation: Option<String>,
 }
 
 impl TimeWarriorLine {
+    /// Starts building an interval beginning at `from`
+    pub fn builder(from: DateTime<Utc>) -> TimeWarriorLineBuilder {
+        TimeWarriorLineBuilder::new(from)
+    }
+
+    /// Parses a line with the given options, collecting the problems lenient
+    /// parsing recovered from as warnings
+    pub fn parse_with(
+        line: &str,
+        options: ParseOptions,
+    ) -> Result<ParsedLine, TimeWarriorLineError> {
+        let mut warnings = Vec::new();
+        let line = parse_line(line, options, &mut warnings)?.into_owned();
+        Ok(ParsedLine { line, warnings })
+    }
+
+    /// Parses a line, recovering from everything but broken timestamps
+    pub fn parse_lenient(line: &str) -> Result<Self, TimeWarriorLineError> {
+        TimeWarriorLineRef::parse_lenient(line).map(TimeWarriorLineRef::into_owned)
+    }
+
+    /// The record type, `RecordKind::Inc` for intervals
+    pub fn kind(&self) -> &RecordKind {
+        &self.tw_type
+    }
+
+    pub fn from(&self) -> DateTime<Utc> {
+        self.from
+    }
+
+    /// The end of the interval, `None` while it is still active
+    pub fn until(&self) -> Option<DateTime<Utc>> {
+        self.until
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn annotation(&self) -> Option<&str> {
+        self.annotation.as_deref()
+    }
+
+    /// An interval is active while it has no end date
+    pub fn is_active(&self) -> bool {
+        self.until.is_none()
+    }
+
+    /// Duration of the interval, active intervals are measured up to now
     pub fn duration(&self) -> chrono::Duration {
-        self.until - self.from
+        self.duration_at(Utc::now())
+    }
+
+    /// Duration of the interval, active intervals are measured up to `now`
+    pub fn duration_at(&self, now: DateTime<Utc>) -> chrono::Duration {
+        self.until.unwrap_or(now) - self.from
     }
 
     pub fn full_tag(&self) -> String {
@@ -26,146 +117,446 @@ impl TimeWarriorLine {
     }
 
     #[deprecated(since = "0.1.4", note = "please use `get_day_naive` instead")]
+    #[allow(deprecated)]
     pub fn get_day(&self) -> Date<Utc> {
         self.from.date()
     }
 }
 
-#[derive(Debug)]
-pub enum TimeWarriorLineError {
-    Generic(String),
-    NoDate(),
+/// How forgiving the line parser is
+///
+/// Strict parsing only accepts lines in the form timewarrior writes them.
+/// Lenient parsing keeps unknown record types, closes unterminated quotes at
+/// the end of the line and takes words missing their leading `#` as tags,
+/// reporting each of these as a warning. Broken timestamps are errors in both
+/// modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    lenient: bool,
 }
 
-impl FromStr for TimeWarriorLine {
-    type Err = TimeWarriorLineError;
+impl ParseOptions {
+    pub fn strict() -> Self {
+        ParseOptions { lenient: false }
+    }
 
-    // Parses a timewarrior line
-    fn from_str(line: &str) -> Result<Self, Self::Err> {
-        let mut parts = line.split_whitespace();
+    pub fn lenient() -> Self {
+        ParseOptions { lenient: true }
+    }
 
-        let tw_type = match parts.next() {
-            Some(a) => a.to_owned(),
-            _ => {
-                return Err(TimeWarriorLineError::Generic("Type parsing".to_owned()));
+    pub fn is_strict(&self) -> bool {
+        !self.lenient
+    }
+}
+
+/// A parsed line along with the problems lenient parsing recovered from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedLine {
+    pub line: TimeWarriorLine,
+    pub warnings: Vec<TimeWarriorLineError>,
+}
+
+/// The record types found at the start of a data line
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RecordKind {
+    /// A tracked interval, the only record type timewarrior writes
+    Inc,
+    /// An unknown record type, only produced by lenient parsing
+    Other(String),
+}
+
+impl RecordKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RecordKind::Inc => "inc",
+            RecordKind::Other(kind) => kind,
+        }
+    }
+
+    pub(crate) fn from_word(word: &str) -> Self {
+        match word {
+            "inc" => RecordKind::Inc,
+            other => RecordKind::Other(other.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for TimeWarriorLine {
+    /// Writes the line in the same format timewarrior uses in its data files
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.tw_type, format_timestamp(&self.from))?;
+        if let Some(until) = &self.until {
+            write!(f, " - {}", format_timestamp(until))?;
+        }
+
+        if !self.tags.is_empty() || self.annotation.is_some() {
+            write!(f, " #")?;
+            for tag in &self.tags {
+                write!(f, " {}", quote_tag(tag))?;
             }
-        };
-
-        let from = match parts.next() {
-            Some(a) => {
-                let f = match parse_date(a.to_owned()) {
-                    Some(b) => b,
-                    None => {
-                        return Err(TimeWarriorLineError::NoDate());
-                    }
-                };
-                f
+        }
+
+        if let Some(annotation) = &self.annotation {
+            write!(f, " # \"{}\"", escape_quoted(annotation))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a `TimeWarriorLine` without going through the data file format
+#[derive(Debug, Clone)]
+pub struct TimeWarriorLineBuilder {
+    tw_type: RecordKind,
+    from: DateTime<Utc>,
+    until: Option<DateTime<Utc>>,
+    tags: Vec<String>,
+    annotation: Option<String>,
+}
+
+impl TimeWarriorLineBuilder {
+    pub fn new(from: DateTime<Utc>) -> Self {
+        TimeWarriorLineBuilder {
+            tw_type: RecordKind::Inc,
+            from,
+            until: None,
+            tags: Vec::new(),
+            annotation: None,
+        }
+    }
+
+    pub fn kind(mut self, kind: RecordKind) -> Self {
+        self.tw_type = kind;
+        self
+    }
+
+    /// Closes the interval, without an end date it is active
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn tags<I, S>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tags.extend(tags.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn annotation<S: Into<String>>(mut self, annotation: S) -> Self {
+        self.annotation = Some(annotation.into());
+        self
+    }
+
+    /// Builds the line, failing for dates the data files cannot hold
+    pub fn build(self) -> Result<TimeWarriorLine, TimestampOutOfRange> {
+        for timestamp in std::iter::once(self.from).chain(self.until) {
+            if !timestamp::is_representable(&timestamp) {
+                return Err(TimestampOutOfRange(timestamp));
             }
-            _ => {
-                return Err(TimeWarriorLineError::NoDate());
+        }
+
+        Ok(TimeWarriorLine {
+            tw_type: self.tw_type,
+            from: self.from,
+            until: self.until,
+            tags: self.tags,
+            annotation: self.annotation,
+        })
+    }
+}
+
+/// Why a data line could not be parsed
+///
+/// Every variant carries the byte offset in the line where the problem starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeWarriorLineError {
+    /// The line does not start with a known record type
+    UnknownType { offset: usize, token: String },
+    /// The start of the interval is missing or not a `YYYYMMDDTHHMMSSZ` timestamp
+    BadStart { offset: usize, token: String },
+    /// The end of the interval is missing or not a `YYYYMMDDTHHMMSSZ` timestamp
+    BadEnd { offset: usize, token: String },
+    /// Neither `-` nor `#` follows the start of the interval
+    UnexpectedToken { offset: usize, token: String },
+    /// A quote in the tag or annotation section is never closed
+    UnterminatedQuote { offset: usize },
+    /// Something other than `#` follows the end of the interval
+    TrailingGarbage { offset: usize, token: String },
+}
+
+impl TimeWarriorLineError {
+    /// Byte offset in the line where the problem starts
+    pub fn offset(&self) -> usize {
+        match self {
+            TimeWarriorLineError::UnknownType { offset, .. }
+            | TimeWarriorLineError::BadStart { offset, .. }
+            | TimeWarriorLineError::BadEnd { offset, .. }
+            | TimeWarriorLineError::UnexpectedToken { offset, .. }
+            | TimeWarriorLineError::UnterminatedQuote { offset }
+            | TimeWarriorLineError::TrailingGarbage { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for TimeWarriorLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeWarriorLineError::UnknownType { offset, token } => {
+                write!(f, "unknown record type {:?} at byte {}", token, offset)
             }
-        };
-
-        let mut active = false;
-        let until: DateTime<Utc> = match parts.next() {
-            // no end date but tags
-            Some("#") => {
-                active = true;
-                Utc::now()
+            TimeWarriorLineError::BadStart { offset, token } if token.is_empty() => {
+                write!(f, "missing start timestamp at byte {}", offset)
             }
-            // end date set
-            Some("-") => {
-                let utc = match parts.next() {
-                    Some(u) => {
-                        let stuff = parts.next();
-                        match stuff {
-                            Some("#") => (),
-                            None => (),
-                            _ => {
-                                return Err(TimeWarriorLineError::Generic(
-                                    format!("Unexpected {:?}", stuff).to_owned(),
-                                ));
-                            }
-                        }
-                        let f = match parse_date(u.to_owned()) {
-                            Some(a) => a,
-                            None => {
-                                return Err(TimeWarriorLineError::Generic(
-                                    format!("Unexpected {:?}", u).to_owned(),
-                                ));
-                            }
-                        };
-                        f
-                    }
-                    None => {
-                        return Err(TimeWarriorLineError::Generic("nope".to_owned()));
-                    }
-                };
-                utc
+            TimeWarriorLineError::BadStart { offset, token } => {
+                write!(f, "bad start timestamp {:?} at byte {}", token, offset)
+            }
+            TimeWarriorLineError::BadEnd { offset, token } if token.is_empty() => {
+                write!(f, "missing end timestamp at byte {}", offset)
+            }
+            TimeWarriorLineError::BadEnd { offset, token } => {
+                write!(f, "bad end timestamp {:?} at byte {}", token, offset)
+            }
+            TimeWarriorLineError::UnexpectedToken { offset, token } => {
+                write!(
+                    f,
+                    "unexpected {:?} at byte {}, expected '-' or '#'",
+                    token, offset
+                )
             }
-            // no enddate and no tags
-            None => {
-                active = true;
-                Utc::now()
+            TimeWarriorLineError::UnterminatedQuote { offset } => {
+                write!(f, "unterminated quote starting at byte {}", offset)
             }
-            // everything else is an error
-            e => {
-                return Err(TimeWarriorLineError::Generic(
-                    format!("Unexpected {:?}", e).to_owned(),
-                ));
+            TimeWarriorLineError::TrailingGarbage { offset, token } => {
+                write!(
+                    f,
+                    "unexpected {:?} after the end timestamp at byte {}",
+                    token, offset
+                )
             }
-        };
+        }
+    }
+}
 
-        let str_nums: Vec<String> = parts.map(|n| n.to_string()).collect();
+impl std::error::Error for TimeWarriorLineError {}
+
+impl FromStr for TimeWarriorLine {
+    type Err = TimeWarriorLineError;
 
-        let tagline = str_nums.join(" ");
+    // Parses a timewarrior line in strict mode
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        TimeWarriorLineRef::parse(line).map(TimeWarriorLineRef::into_owned)
+    }
+}
 
-        let mut multitag = false;
-        let mut tag_string = "".to_owned();
-        let mut tags = Vec::<String>::new();
-        for one_char in tagline.chars() {
-            match one_char {
-                '"' => {
-                    multitag = !multitag;
-                }
-                ' ' => {
-                    if multitag {
-                        tag_string.push(' ');
-                    } else {
-                        tags.push(tag_string);
-                        tag_string = "".to_owned();
+struct Parser<'a, 'w> {
+    lexer: Lexer<'a>,
+    options: ParseOptions,
+    warnings: &'w mut Vec<TimeWarriorLineError>,
+}
+
+impl<'a, 'w> Parser<'a, 'w> {
+    fn next(&mut self) -> Result<Option<Token<'a>>, TimeWarriorLineError> {
+        let token = self.lexer.next();
+        if let Some(offset) = token.and_then(|t| t.unterminated_quote) {
+            // lenient parsing closes the quote at the end of the line
+            self.recover(TimeWarriorLineError::UnterminatedQuote { offset })?;
+        }
+        Ok(token)
+    }
+
+    fn recover(&mut self, problem: TimeWarriorLineError) -> Result<(), TimeWarriorLineError> {
+        if self.options.is_strict() {
+            Err(problem)
+        } else {
+            self.warnings.push(problem);
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn parse_line<'a>(
+    line: &'a str,
+    options: ParseOptions,
+    warnings: &mut Vec<TimeWarriorLineError>,
+) -> Result<TimeWarriorLineRef<'a>, TimeWarriorLineError> {
+    let mut parser = Parser {
+        lexer: Lexer::new(line),
+        options,
+        warnings,
+    };
+    let end_of_line = line.len();
+
+    let tw_type = match parser.next()? {
+        Some(token) if !token.quoted => {
+            if let RecordKind::Other(_) = RecordKind::from_word(token.raw) {
+                parser.recover(TimeWarriorLineError::UnknownType {
+                    offset: token.offset,
+                    token: token.raw.to_owned(),
+                })?;
+            }
+            token.raw
+        }
+        Some(token) => {
+            return Err(TimeWarriorLineError::UnknownType {
+                offset: token.offset,
+                token: token.raw.to_owned(),
+            });
+        }
+        None => {
+            return Err(TimeWarriorLineError::UnknownType {
+                offset: end_of_line,
+                token: "".to_owned(),
+            });
+        }
+    };
+
+    let from = match parser.next()? {
+        Some(token) => match parse_timestamp(token.raw) {
+            Some(date) if !token.quoted => date,
+            _ => {
+                return Err(TimeWarriorLineError::BadStart {
+                    offset: token.offset,
+                    token: token.raw.to_owned(),
+                });
+            }
+        },
+        None => {
+            return Err(TimeWarriorLineError::BadStart {
+                offset: end_of_line,
+                token: "".to_owned(),
+            });
+        }
+    };
+
+    // start of the tag section, lenient parsing takes words without a leading
+    // `#` as tags
+    let mut tags_start = None;
+    let until = match parser.next()? {
+        // no enddate and no tags
+        None => None,
+        // no end date but tags
+        Some(token) if token.is("#") => {
+            tags_start = Some(token.offset + 1);
+            None
+        }
+        // end date set
+        Some(token) if token.is("-") => {
+            let until = match parser.next()? {
+                Some(token) => match parse_timestamp(token.raw) {
+                    Some(date) if !token.quoted => date,
+                    _ => {
+                        return Err(TimeWarriorLineError::BadEnd {
+                            offset: token.offset,
+                            token: token.raw.to_owned(),
+                        });
                     }
+                },
+                None => {
+                    return Err(TimeWarriorLineError::BadEnd {
+                        offset: end_of_line,
+                        token: "".to_owned(),
+                    });
                 }
-                c => {
-                    tag_string.push(c);
+            };
+
+            match parser.next()? {
+                None => (),
+                Some(token) if token.is("#") => tags_start = Some(token.offset + 1),
+                Some(token) => {
+                    parser.recover(TimeWarriorLineError::TrailingGarbage {
+                        offset: token.offset,
+                        token: token.raw.to_owned(),
+                    })?;
+                    tags_start = Some(token.offset);
                 }
             }
+            Some(until)
         }
-        if tag_string != "" {
-            tags.push(tag_string);
+        // everything else is an error
+        Some(token) => {
+            parser.recover(TimeWarriorLineError::UnexpectedToken {
+                offset: token.offset,
+                token: token.raw.to_owned(),
+            })?;
+            tags_start = Some(token.offset);
+            None
         }
+    };
 
-        Ok(TimeWarriorLine {
-            tw_type: tw_type,
-            from: from,
-            until: until,
-            tags: tags,
-            active: active,
-        })
+    let mut tags = "";
+    let mut annotation = None;
+    if let Some(tags_start) = tags_start {
+        let mut tags_end = end_of_line;
+        // lexing up to the end checks the quotes of the annotation as well
+        while let Some(token) = parser.next()? {
+            // a second `#` separates the tags from the annotation
+            if annotation.is_none() && token.is("#") {
+                tags_end = token.offset;
+                annotation = Some(&line[token.offset + 1..]);
+            }
+        }
+        tags = &line[tags_start..tags_end];
     }
+
+    Ok(TimeWarriorLineRef {
+        kind: tw_type,
+        from,
+        until,
+        tags,
+        annotation,
+    })
 }
 
-fn parse_date(date_string: String) -> Option<DateTime<Utc>> {
-    let from_part = format!("{} +0000", date_string);
+// Same rules as timewarrior's `quoteIfNeeded`, plus everything our own tokenizer
+// would otherwise split on
+fn quote_tag(tag: &str) -> String {
+    let needs_quotes = tag.is_empty()
+        || tag
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "\"\\#+-/()<^!=~_%".contains(c));
 
-    let date = match DateTime::parse_from_str(&from_part, "%Y%m%dT%H%M%SZ %z") {
-        Ok(a) => Utc.from_local_datetime(&a.naive_local()).single(),
-        Err(_) => None,
-    };
-    date
+    if needs_quotes {
+        format!("\"{}\"", escape_quoted(tag))
+    } else {
+        tag.to_owned()
+    }
+}
+
+fn escape_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{b}' => escaped.push_str("\\v"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -247,8 +638,8 @@ mod tests {
 
         let line = result.unwrap();
 
-        assert_eq!(line.tw_type, "inc");
-        assert_eq!(line.active, true);
+        assert_eq!(line.tw_type, RecordKind::Inc);
+        assert_eq!(line.is_active(), true);
         assert_eq!(line.tags, Vec::<String>::new());
 
         assert_eq!(line.full_tag(), "".to_owned());
@@ -264,8 +655,8 @@ mod tests {
 
         let line = result.unwrap();
 
-        assert_eq!(line.tw_type, "inc");
-        assert_eq!(line.active, true);
+        assert_eq!(line.tw_type, RecordKind::Inc);
+        assert_eq!(line.is_active(), true);
         assert_eq!(line.tags, vec!["Walala"]);
 
         assert_eq!(line.full_tag(), "Walala".to_owned());
@@ -286,8 +677,8 @@ mod tests {
 
         let line = result.unwrap();
 
-        assert_eq!(line.tw_type, "inc");
-        assert_eq!(line.active, false);
+        assert_eq!(line.tw_type, RecordKind::Inc);
+        assert_eq!(line.is_active(), false);
         assert_eq!(line.tags, Vec::<String>::new());
 
         assert_eq!(line.full_tag(), "".to_owned());
@@ -295,8 +686,14 @@ mod tests {
         assert_eq!(line.from.format("%Y-%m-%d").to_string(), "2000-10-11");
         assert_eq!(line.from.format("%H:%M:%S").to_string(), "13:30:55");
 
-        assert_eq!(line.until.format("%Y-%m-%d").to_string(), "2000-11-12");
-        assert_eq!(line.until.format("%H:%M:%S").to_string(), "14:40:54");
+        assert_eq!(
+            line.until.unwrap().format("%Y-%m-%d").to_string(),
+            "2000-11-12"
+        );
+        assert_eq!(
+            line.until.unwrap().format("%H:%M:%S").to_string(),
+            "14:40:54"
+        );
     }
 
     #[test]
@@ -333,6 +730,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn date_is_correct() {
         let result = TimeWarriorLine::from_str("inc 20001011T133055Z - 20001011T134055Z");
         assert_eq!(
@@ -359,8 +757,8 @@ mod tests {
 
         let line = result.unwrap();
 
-        assert_eq!(line.tw_type, "inc");
-        assert_eq!(line.active, false);
+        assert_eq!(line.tw_type, RecordKind::Inc);
+        assert_eq!(line.is_active(), false);
         assert_eq!(line.tags, vec!["Buvere"]);
 
         assert_eq!(line.full_tag(), "Buvere".to_owned());
@@ -368,8 +766,14 @@ mod tests {
         assert_eq!(line.from.format("%Y-%m-%d").to_string(), "2000-10-11");
         assert_eq!(line.from.format("%H:%M:%S").to_string(), "13:30:55");
 
-        assert_eq!(line.until.format("%Y-%m-%d").to_string(), "2000-11-12");
-        assert_eq!(line.until.format("%H:%M:%S").to_string(), "14:40:54");
+        assert_eq!(
+            line.until.unwrap().format("%Y-%m-%d").to_string(),
+            "2000-11-12"
+        );
+        assert_eq!(
+            line.until.unwrap().format("%H:%M:%S").to_string(),
+            "14:40:54"
+        );
     }
 
     #[test]
@@ -386,12 +790,494 @@ mod tests {
 
         let line = result.unwrap();
 
-        assert_eq!(line.tw_type, "inc");
-        assert_eq!(line.active, false);
+        assert_eq!(line.tw_type, RecordKind::Inc);
+        assert_eq!(line.is_active(), false);
         assert_eq!(line.tags, vec!["ABC CDE", "EFG", "HIJ"]);
 
         // assert_eq!(line.full_tag(), "\"ABC CDE\" EFG HIJ".to_owned());
 
         assert_eq!(line.tags.len(), 3);
     }
+
+    #[test]
+    fn annotation_after_tags_is_recognized() {
+        let result = TimeWarriorLine::from_str(
+            "inc 20001011T133055Z - 20001112T144054Z # ABC \"DEF GHI\" # \"some annotation\"",
+        );
+        assert_eq!(
+            result.is_ok(),
+            true,
+            "parsed line is not a ok result {:?}",
+            result
+        );
+
+        let line = result.unwrap();
+
+        assert_eq!(line.tags, vec!["ABC", "DEF GHI"]);
+        assert_eq!(line.annotation, Some("some annotation".to_owned()));
+    }
+
+    #[test]
+    fn annotation_without_tags_is_recognized() {
+        let result = TimeWarriorLine::from_str("inc 20001011T133055Z # # \"only a note\"");
+        assert_eq!(
+            result.is_ok(),
+            true,
+            "parsed line is not a ok result {:?}",
+            result
+        );
+
+        let line = result.unwrap();
+
+        assert_eq!(line.is_active(), true);
+        assert_eq!(line.tags, Vec::<String>::new());
+        assert_eq!(line.annotation, Some("only a note".to_owned()));
+    }
+
+    #[test]
+    fn annotation_with_escaped_quotes() {
+        let result = TimeWarriorLine::from_str(
+            "inc 20001011T133055Z - 20001112T144054Z # Buvere # \"say \\\"hi\\\" # twice\"",
+        );
+        assert_eq!(
+            result.is_ok(),
+            true,
+            "parsed line is not a ok result {:?}",
+            result
+        );
+
+        let line = result.unwrap();
+
+        assert_eq!(line.tags, vec!["Buvere"]);
+        assert_eq!(line.annotation, Some("say \"hi\" # twice".to_owned()));
+    }
+
+    #[test]
+    fn no_annotation_without_second_hash() {
+        let result = TimeWarriorLine::from_str("inc 20001011T133055Z - 20001112T144054Z # Buvere");
+        let line = result.unwrap();
+
+        assert_eq!(line.annotation, None);
+    }
+
+    #[test]
+    fn getters_expose_parsed_fields() {
+        let line = TimeWarriorLine::from_str(
+            "inc 20001011T133055Z - 20001011T134055Z # Buvere # \"a note\"",
+        )
+        .unwrap();
+
+        assert_eq!(line.kind(), &RecordKind::Inc);
+        assert_eq!(line.is_active(), false);
+        assert_eq!(line.tags(), ["Buvere".to_owned()]);
+        assert_eq!(line.annotation(), Some("a note"));
+        assert_eq!(
+            line.from(),
+            Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap()
+        );
+        assert_eq!(
+            line.until(),
+            Some(Utc.with_ymd_and_hms(2000, 10, 11, 13, 40, 55).unwrap())
+        );
+    }
+
+    #[test]
+    fn builder_creates_closed_interval() {
+        let from = Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap();
+        let until = Utc.with_ymd_and_hms(2000, 10, 11, 14, 30, 55).unwrap();
+        let line = TimeWarriorLine::builder(from)
+            .until(until)
+            .tag("ABC CDE")
+            .tags(vec!["EFG", "HIJ"])
+            .annotation("a note")
+            .build()
+            .unwrap();
+
+        assert_eq!(line.kind(), &RecordKind::Inc);
+        assert_eq!(line.is_active(), false);
+        assert_eq!(line.from(), from);
+        assert_eq!(line.until(), Some(until));
+        assert_eq!(line.tags(), ["ABC CDE", "EFG", "HIJ"]);
+        assert_eq!(line.annotation(), Some("a note"));
+        assert_eq!(line.duration(), chrono::Duration::hours(1));
+    }
+
+    #[test]
+    fn builder_without_end_is_active() {
+        let from = Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap();
+        let line = TimeWarriorLine::builder(from).build().unwrap();
+
+        assert_eq!(line.is_active(), true);
+        assert_eq!(line.tags().len(), 0);
+        assert_eq!(line.annotation(), None);
+    }
+
+    #[test]
+    fn builder_rejects_years_the_data_files_cannot_hold() {
+        let last = Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap();
+        let line = TimeWarriorLine::builder(last).build().unwrap();
+        assert_eq!(TimeWarriorLine::from_str(&line.to_string()), Ok(line));
+
+        let after = last + chrono::Duration::seconds(1);
+        assert_eq!(
+            TimeWarriorLine::builder(after).build(),
+            Err(TimestampOutOfRange(after))
+        );
+        assert_eq!(
+            TimeWarriorLine::builder(last).until(after).build(),
+            Err(TimestampOutOfRange(after))
+        );
+
+        let before = Utc.with_ymd_and_hms(-1, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(
+            TimeWarriorLine::builder(before).build(),
+            Err(TimestampOutOfRange(before))
+        );
+    }
+
+    #[test]
+    fn display_writes_data_file_format() {
+        let from = Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap();
+        let until = Utc.with_ymd_and_hms(2000, 11, 12, 14, 40, 54).unwrap();
+
+        let line = TimeWarriorLine::builder(from).build().unwrap();
+        assert_eq!(line.to_string(), "inc 20001011T133055Z");
+
+        let line = TimeWarriorLine::builder(from).until(until).build().unwrap();
+        assert_eq!(line.to_string(), "inc 20001011T133055Z - 20001112T144054Z");
+
+        let line = TimeWarriorLine::builder(from)
+            .until(until)
+            .tags(vec!["ABC CDE", "EFG", "foo-bar"])
+            .build()
+            .unwrap();
+        assert_eq!(
+            line.to_string(),
+            "inc 20001011T133055Z - 20001112T144054Z # \"ABC CDE\" EFG \"foo-bar\""
+        );
+
+        let line = TimeWarriorLine::builder(from)
+            .annotation("say \"hi\"")
+            .build()
+            .unwrap();
+        assert_eq!(
+            line.to_string(),
+            "inc 20001011T133055Z # # \"say \\\"hi\\\"\""
+        );
+    }
+
+    #[test]
+    fn serialized_lines_parse_back_to_the_same_line() {
+        let vectors = vec![
+            "inc 20001011T133055Z",
+            "inc 20001011T133055Z # Walala",
+            "inc 20001011T133055Z - 20001112T144054Z",
+            "inc 20001011T133055Z - 20001011T134055Z",
+            "inc 20001011T133055Z - 20001112T144054Z # Buvere",
+            "inc 20001011T133055Z - 20001112T144054Z # \"ABC CDE\" EFG HIJ",
+            "inc 20001011T133055Z - 20001112T144054Z # ABC \"DEF GHI\" # \"some annotation\"",
+            "inc 20001011T133055Z # # \"only a note\"",
+            "inc 20001011T133055Z - 20001112T144054Z # Buvere # \"say \\\"hi\\\" # twice\"",
+        ];
+
+        for vector in vectors {
+            let line = TimeWarriorLine::from_str(vector).unwrap();
+            let serialized = line.to_string();
+            let reparsed = TimeWarriorLine::from_str(&serialized);
+
+            assert_eq!(
+                reparsed.is_ok(),
+                true,
+                "serialized {:?} of {:?} does not parse: {:?}",
+                serialized,
+                vector,
+                reparsed
+            );
+            assert_eq!(reparsed.unwrap(), line, "round trip of {:?}", vector);
+            assert_eq!(serialized, vector);
+        }
+    }
+
+    #[test]
+    fn open_interval_has_no_end() {
+        let line = TimeWarriorLine::from_str("inc 20001011T133055Z # Walala").unwrap();
+
+        assert_eq!(line.until(), None);
+        assert_eq!(
+            line,
+            TimeWarriorLine::from_str("inc 20001011T133055Z # Walala").unwrap(),
+            "parsing an open interval should be deterministic"
+        );
+    }
+
+    #[test]
+    fn duration_at_uses_given_clock_for_open_intervals() {
+        let line = TimeWarriorLine::from_str("inc 20001011T133055Z # Walala").unwrap();
+        let now = Utc.with_ymd_and_hms(2000, 10, 11, 15, 30, 55).unwrap();
+
+        assert_eq!(line.duration_at(now), chrono::Duration::hours(2));
+        assert_eq!(line.duration_at(now), line.duration_at(now));
+    }
+
+    #[test]
+    fn duration_at_ignores_clock_for_closed_intervals() {
+        let line = TimeWarriorLine::from_str("inc 20001011T133055Z - 20001011T134055Z").unwrap();
+        let now = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(line.duration_at(now), chrono::Duration::minutes(10));
+    }
+
+    #[test]
+    fn errors_point_at_the_broken_token() {
+        let cases = vec![
+            (
+                "",
+                TimeWarriorLineError::UnknownType {
+                    offset: 0,
+                    token: "".to_owned(),
+                },
+            ),
+            (
+                "inc",
+                TimeWarriorLineError::BadStart {
+                    offset: 3,
+                    token: "".to_owned(),
+                },
+            ),
+            (
+                "inc 20001011T133055CEST",
+                TimeWarriorLineError::BadStart {
+                    offset: 4,
+                    token: "20001011T133055CEST".to_owned(),
+                },
+            ),
+            (
+                "inc 20001011T133055Z sadasds",
+                TimeWarriorLineError::UnexpectedToken {
+                    offset: 21,
+                    token: "sadasds".to_owned(),
+                },
+            ),
+            (
+                "inc 20001011T133055Z - sdsadsad",
+                TimeWarriorLineError::BadEnd {
+                    offset: 23,
+                    token: "sdsadsad".to_owned(),
+                },
+            ),
+            (
+                "inc 20001011T133055Z - ",
+                TimeWarriorLineError::BadEnd {
+                    offset: 23,
+                    token: "".to_owned(),
+                },
+            ),
+            (
+                "inc 20001011T133055Z - 20001011T183055Z dsafsadsads",
+                TimeWarriorLineError::TrailingGarbage {
+                    offset: 40,
+                    token: "dsafsadsads".to_owned(),
+                },
+            ),
+            (
+                "inc 20001011T133055Z # ABC \"DEF GHI",
+                TimeWarriorLineError::UnterminatedQuote { offset: 27 },
+            ),
+        ];
+
+        for (line, expected) in cases {
+            let result = TimeWarriorLine::from_str(line);
+            assert_eq!(result, Err(expected.clone()), "parsing {:?}", line);
+            assert_eq!(expected.offset(), result.unwrap_err().offset());
+        }
+    }
+
+    #[test]
+    fn errors_are_displayed_with_position() {
+        let error = TimeWarriorLine::from_str("inc 20001011T133055Z - sdsadsad").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "bad end timestamp \"sdsadsad\" at byte 23"
+        );
+
+        let error: Box<dyn std::error::Error> = Box::new(error);
+        assert_eq!(
+            error.to_string(),
+            "bad end timestamp \"sdsadsad\" at byte 23"
+        );
+    }
+
+    #[test]
+    fn unknown_record_type_is_rejected() {
+        let result = TimeWarriorLine::from_str("afdf 20001011T133055Z - 20001011T134055Z");
+
+        assert_eq!(
+            result,
+            Err(TimeWarriorLineError::UnknownType {
+                offset: 0,
+                token: "afdf".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_record_type_is_kept_when_lenient() {
+        let line = TimeWarriorLine::parse_lenient("afdf 20001011T133055Z - 20001011T134055Z # foo")
+            .unwrap();
+
+        assert_eq!(line.kind(), &RecordKind::Other("afdf".to_owned()));
+        assert_eq!(line.tags(), ["foo"]);
+        assert_eq!(
+            line.to_string(),
+            "afdf 20001011T133055Z - 20001011T134055Z # foo"
+        );
+
+        let line = TimeWarriorLine::parse_lenient("inc 20001011T133055Z").unwrap();
+        assert_eq!(line.kind(), &RecordKind::Inc);
+    }
+
+    #[test]
+    fn strict_parsing_has_no_warnings() {
+        let parsed = TimeWarriorLine::parse_with(
+            "inc 20001011T133055Z - 20001112T144054Z # Buvere",
+            ParseOptions::strict(),
+        )
+        .unwrap();
+
+        assert_eq!(parsed.line.tags(), ["Buvere"]);
+        assert_eq!(parsed.warnings, vec![]);
+        assert_eq!(ParseOptions::default(), ParseOptions::strict());
+    }
+
+    #[test]
+    fn lenient_parsing_closes_unterminated_quotes() {
+        let line = "inc 20001011T133055Z # ABC \"DEF GHI";
+
+        assert_eq!(
+            TimeWarriorLine::parse_with(line, ParseOptions::strict()),
+            Err(TimeWarriorLineError::UnterminatedQuote { offset: 27 })
+        );
+
+        let parsed = TimeWarriorLine::parse_with(line, ParseOptions::lenient()).unwrap();
+        assert_eq!(parsed.line.tags(), ["ABC", "DEF GHI"]);
+        assert_eq!(
+            parsed.warnings,
+            vec![TimeWarriorLineError::UnterminatedQuote { offset: 27 }]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_takes_words_without_hash_as_tags() {
+        let parsed = TimeWarriorLine::parse_with(
+            "inc 20001011T133055Z - 20001011T183055Z dsafsadsads foo",
+            ParseOptions::lenient(),
+        )
+        .unwrap();
+        assert_eq!(parsed.line.tags(), ["dsafsadsads", "foo"]);
+        assert_eq!(parsed.line.is_active(), false);
+        assert_eq!(
+            parsed.warnings,
+            vec![TimeWarriorLineError::TrailingGarbage {
+                offset: 40,
+                token: "dsafsadsads".to_owned(),
+            }]
+        );
+
+        let parsed =
+            TimeWarriorLine::parse_with("inc 20001011T133055Z sadasds", ParseOptions::lenient())
+                .unwrap();
+        assert_eq!(parsed.line.tags(), ["sadasds"]);
+        assert_eq!(parsed.line.is_active(), true);
+        assert_eq!(
+            parsed.warnings,
+            vec![TimeWarriorLineError::UnexpectedToken {
+                offset: 21,
+                token: "sadasds".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_still_rejects_broken_timestamps() {
+        let lines = vec![
+            "afdf dafdf dsfads fdsaf",
+            "inc 20001011T133055CEST",
+            "inc 20001011T133055Z - sdsadsad",
+            "inc 20001011T133055Z - ",
+        ];
+
+        for line in lines {
+            let result = TimeWarriorLine::parse_with(line, ParseOptions::lenient());
+            assert_eq!(result.is_err(), true, "{:?} should not be parsed", line);
+        }
+    }
+
+    #[test]
+    fn quoted_tags_with_escaped_quotes() {
+        let line = TimeWarriorLine::from_str(
+            r#"inc 20001011T133055Z - 20001112T144054Z # "say \"hi\"" other"#,
+        )
+        .unwrap();
+
+        assert_eq!(line.tags(), [r#"say "hi""#, "other"]);
+    }
+
+    #[test]
+    fn quoted_tags_with_backslashes() {
+        let line = TimeWarriorLine::from_str(
+            r#"inc 20001011T133055Z - 20001112T144054Z # "C:\\temp" "tab\there" "\u00e4""#,
+        )
+        .unwrap();
+
+        assert_eq!(line.tags(), [r"C:\temp", "tab\there", "ä"]);
+    }
+
+    #[test]
+    fn quoted_tags_with_hashes() {
+        let line = TimeWarriorLine::from_str(
+            r##"inc 20001011T133055Z - 20001112T144054Z # "#" "issue #42" a#b # "note""##,
+        )
+        .unwrap();
+
+        assert_eq!(line.tags(), ["#", "issue #42", "a#b"]);
+        assert_eq!(line.annotation(), Some("note"));
+    }
+
+    #[test]
+    fn quoted_tags_keep_runs_of_spaces() {
+        let line = TimeWarriorLine::from_str(
+            "inc 20001011T133055Z - 20001112T144054Z #   \"two  spaces\"   \"  padded \" # \"a   b\"",
+        )
+        .unwrap();
+
+        assert_eq!(line.tags(), ["two  spaces", "  padded "]);
+        assert_eq!(line.annotation(), Some("a   b"));
+    }
+
+    #[test]
+    fn escaped_tags_round_trip() {
+        let from = Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap();
+        let line = TimeWarriorLine::builder(from)
+            .tags(vec![
+                r#"say "hi""#,
+                r"C:\temp",
+                "#",
+                "issue #42",
+                "two  spaces",
+                "line\nbreak",
+                "bell\u{7}",
+                "",
+            ])
+            .annotation("quote \" and backslash \\ and\ttab")
+            .build()
+            .unwrap();
+
+        let serialized = line.to_string();
+        assert_eq!(
+            serialized,
+            r##"inc 20001011T133055Z # "say \"hi\"" "C:\\temp" "#" "issue #42" "two  spaces" "line\nbreak" "bell\u0007" "" # "quote \" and backslash \\ and\ttab""##
+        );
+        assert_eq!(TimeWarriorLine::from_str(&serialized).unwrap(), line);
+    }
 }
@@ -0,0 +1,190 @@
+use crate::lexer::Lexer;
+use crate::{parse_line, ParseOptions, RecordKind, TimeWarriorLine, TimeWarriorLineError};
+use chrono::prelude::*;
+use std::borrow::Cow;
+
+/// A parsed data line borrowing its tags and annotation from the input
+///
+/// Parsing does not allocate, tags are sliced out of the line while iterating
+/// and only escaped tags are copied. Use `into_owned` to keep a line around
+/// longer than its input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWarriorLineRef<'a> {
+    pub(crate) kind: &'a str,
+    pub(crate) from: DateTime<Utc>,
+    pub(crate) until: Option<DateTime<Utc>>,
+    /// the raw tag section between the first and the second `#`
+    pub(crate) tags: &'a str,
+    /// the raw annotation section behind the second `#`
+    pub(crate) annotation: Option<&'a str>,
+}
+
+impl<'a> TimeWarriorLineRef<'a> {
+    /// Parses a line in strict mode
+    pub fn parse(line: &'a str) -> Result<Self, TimeWarriorLineError> {
+        parse_line(line, ParseOptions::strict(), &mut Vec::new())
+    }
+
+    /// Parses a line, recovering from everything but broken timestamps
+    pub fn parse_lenient(line: &'a str) -> Result<Self, TimeWarriorLineError> {
+        parse_line(line, ParseOptions::lenient(), &mut Vec::new())
+    }
+
+    /// The record type, `RecordKind::Inc` for intervals
+    pub fn kind(&self) -> RecordKind {
+        RecordKind::from_word(self.kind)
+    }
+
+    pub fn from(&self) -> DateTime<Utc> {
+        self.from
+    }
+
+    /// The end of the interval, `None` while it is still active
+    pub fn until(&self) -> Option<DateTime<Utc>> {
+        self.until
+    }
+
+    /// An interval is active while it has no end date
+    pub fn is_active(&self) -> bool {
+        self.until.is_none()
+    }
+
+    /// Duration of the interval, active intervals are measured up to `now`
+    pub fn duration_at(&self, now: DateTime<Utc>) -> chrono::Duration {
+        self.until.unwrap_or(now) - self.from
+    }
+
+    pub fn tags(&self) -> Tags<'a> {
+        Tags {
+            lexer: Lexer::new(self.tags),
+        }
+    }
+
+    pub fn annotation(&self) -> Option<Cow<'a, str>> {
+        self.annotation.map(|raw| {
+            let mut tokens = Lexer::new(raw);
+            match (tokens.next(), tokens.next()) {
+                (None, _) => Cow::Borrowed(""),
+                (Some(token), None) => token.text(),
+                (Some(first), Some(second)) => {
+                    let mut text = first.text().into_owned();
+                    for token in std::iter::once(second).chain(tokens) {
+                        text.push(' ');
+                        text.push_str(&token.text());
+                    }
+                    Cow::Owned(text)
+                }
+            }
+        })
+    }
+
+    pub fn into_owned(self) -> TimeWarriorLine {
+        TimeWarriorLine {
+            tw_type: self.kind(),
+            from: self.from,
+            until: self.until,
+            tags: self.tags().map(Cow::into_owned).collect(),
+            annotation: self.annotation().map(Cow::into_owned),
+        }
+    }
+}
+
+impl<'a> From<TimeWarriorLineRef<'a>> for TimeWarriorLine {
+    fn from(line: TimeWarriorLineRef<'a>) -> Self {
+        line.into_owned()
+    }
+}
+
+/// Iterator over the tags of a `TimeWarriorLineRef`
+#[derive(Clone)]
+pub struct Tags<'a> {
+    lexer: Lexer<'a>,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Cow<'a, str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lexer.next().map(|token| token.text())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_borrowed_from_the_line() {
+        let line = "inc 20001011T133055Z - 20001112T144054Z # ABC \"DEF GHI\" # \"a note\"";
+        let parsed = TimeWarriorLineRef::parse(line).unwrap();
+
+        let tags: Vec<Cow<str>> = parsed.tags().collect();
+        assert_eq!(tags, vec!["ABC", "DEF GHI"]);
+        assert_eq!(matches!(tags[0], Cow::Borrowed(_)), true);
+        assert_eq!(parsed.annotation(), Some(Cow::Borrowed("a note")));
+        assert_eq!(parsed.is_active(), false);
+        assert_eq!(parsed.kind(), RecordKind::Inc);
+    }
+
+    #[test]
+    fn escaped_tags_are_copied() {
+        let line = r#"inc 20001011T133055Z # "say \"hi\"""#;
+        let parsed = TimeWarriorLineRef::parse(line).unwrap();
+
+        let tags: Vec<Cow<str>> = parsed.tags().collect();
+        assert_eq!(tags, vec![r#"say "hi""#]);
+        assert_eq!(matches!(tags[0], Cow::Owned(_)), true);
+        assert_eq!(parsed.annotation(), None);
+        assert_eq!(parsed.is_active(), true);
+    }
+
+    #[test]
+    fn into_owned_copies_all_fields() {
+        let line = "inc 20001011T133055Z - 20001112T144054Z # \"ABC CDE\" EFG # \"say \\\"hi\\\"\"";
+        let owned = TimeWarriorLineRef::parse(line).unwrap().into_owned();
+
+        let from = Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap();
+        let until = Utc.with_ymd_and_hms(2000, 11, 12, 14, 40, 54).unwrap();
+        assert_eq!(
+            owned,
+            TimeWarriorLine::builder(from)
+                .until(until)
+                .tags(vec!["ABC CDE", "EFG"])
+                .annotation("say \"hi\"")
+                .build()
+                .unwrap()
+        );
+
+        let owned: TimeWarriorLine = TimeWarriorLineRef::parse("inc 20001011T133055Z # # \"\"")
+            .unwrap()
+            .into();
+        assert_eq!(
+            owned,
+            TimeWarriorLine::builder(from)
+                .annotation("")
+                .build()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn lenient_parsing_keeps_recovered_tags() {
+        let line = "afdf 20001011T133055Z - 20001011T183055Z dsafsadsads foo";
+
+        assert_eq!(
+            TimeWarriorLineRef::parse(line),
+            Err(TimeWarriorLineError::UnknownType {
+                offset: 0,
+                token: "afdf".to_owned(),
+            })
+        );
+
+        let parsed = TimeWarriorLineRef::parse_lenient(line).unwrap();
+        assert_eq!(parsed.kind(), RecordKind::Other("afdf".to_owned()));
+        assert_eq!(
+            parsed.tags().collect::<Vec<_>>(),
+            vec!["dsafsadsads", "foo"]
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use crate::DatabaseError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long changes wait for a held lock unless told otherwise
+pub(crate) const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait between attempts to take a held lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// An exclusive lock on a file while it is rewritten, released when dropped
+///
+/// This is the lock timewarrior takes on a data file before rewriting it, a
+/// write lock on the whole file through `fcntl` as libshared's `File::lock`
+/// takes it. On Linux it is taken as an open file description lock, which
+/// conflicts with those locks but is neither shared with other handles of
+/// the same process nor released when one of them is closed. Elsewhere it
+/// is an `flock` lock, which BSD and macOS implement as the same lock.
+///
+/// The operating system releases the lock when its process exits, so a
+/// crashed or killed process never leaves a stale lock behind.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileLock {
+    /// Locks the file at `path`, creating it if it is missing and waiting up
+    /// to `timeout` for another process to release it
+    ///
+    /// A file replaced by `replace` while waiting is not locked, the wait
+    /// goes on with the file that took its place.
+    pub fn acquire(path: &Path, timeout: Duration) -> Result<FileLock, DatabaseError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(DatabaseError::io(path))?;
+
+            while !try_lock(&file).map_err(DatabaseError::io(path))? {
+                if Instant::now() >= deadline {
+                    return Err(DatabaseError::Locked {
+                        path: path.to_owned(),
+                        timeout,
+                    });
+                }
+                thread::sleep(RETRY_INTERVAL);
+            }
+
+            if is_current(&file, path).map_err(DatabaseError::io(path))? {
+                return Ok(FileLock {
+                    path: path.to_owned(),
+                    file,
+                });
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the file through a renamed temporary file, a crash leaves
+    /// either the old or the new content behind
+    ///
+    /// The new file is locked before it takes the place of the old one, so
+    /// the lock stays held throughout.
+    pub fn replace(&mut self, content: &str) -> Result<(), DatabaseError> {
+        let file_name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("data");
+        let temp_path = self.path.with_file_name(format!(".{}.tmp", file_name));
+
+        let mut temp = File::create(&temp_path).map_err(DatabaseError::io(&temp_path))?;
+        try_lock(&temp)
+            .and_then(|locked| match locked {
+                true => Ok(()),
+                false => Err(io::ErrorKind::WouldBlock.into()),
+            })
+            .and_then(|_| temp.write_all(content.as_bytes()))
+            .and_then(|_| temp.sync_all())
+            .map_err(DatabaseError::io(&temp_path))?;
+        fs::rename(&temp_path, &self.path).map_err(DatabaseError::io(&self.path))?;
+
+        // closing the replaced file releases its lock, waiters move on
+        self.file = temp;
+        Ok(())
+    }
+}
+
+/// The locks of the files a change rewrites
+///
+/// They are taken in the order of their paths, so changes locking some of
+/// the same files can not deadlock.
+#[derive(Debug)]
+pub(crate) struct FileLocks {
+    locks: BTreeMap<PathBuf, FileLock>,
+}
+
+impl FileLocks {
+    pub(crate) fn acquire<I>(paths: I, timeout: Duration) -> Result<FileLocks, DatabaseError>
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let paths: BTreeSet<PathBuf> = paths.into_iter().collect();
+        let mut locks = BTreeMap::new();
+        for path in paths {
+            let lock = FileLock::acquire(&path, timeout)?;
+            locks.insert(path, lock);
+        }
+        Ok(FileLocks { locks })
+    }
+
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.locks.contains_key(path)
+    }
+
+    /// Replaces one of the locked files, see `FileLock::replace`
+    pub(crate) fn replace(&mut self, path: &Path, content: &str) -> Result<(), DatabaseError> {
+        self.locks
+            .get_mut(path)
+            .unwrap_or_else(|| panic!("{} is not locked", path.display()))
+            .replace(content)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: an all zero `flock` is valid, zero length covers the whole file
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // SAFETY: the descriptor stays open for the call and `lock` outlives it
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &lock) } == 0 {
+        return Ok(true);
+    }
+
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EAGAIN) | Some(libc::EACCES) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_lock(file: &File) -> io::Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(false),
+        Err(std::fs::TryLockError::Error(error)) => Err(error),
+    }
+}
+
+/// Whether `file` is still the file at `path` and was not replaced
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locked = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok((locked.dev(), locked.ino()) == (current.dev(), current.ino())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let dir = TempDir::new("lock-exclusive");
+        let path = dir.write("2020-02.data", "inc 20200202T080000Z\n");
+
+        let lock = FileLock::acquire(&path, Duration::from_millis(0)).unwrap();
+        assert_eq!(lock.path(), path);
+        assert_eq!(dir.read("2020-02.data"), "inc 20200202T080000Z\n");
+
+        let result = FileLock::acquire(&path, Duration::from_millis(50));
+        assert_eq!(
+            matches!(result, Err(DatabaseError::Locked { .. })),
+            true,
+            "{:?}",
+            result
+        );
+
+        drop(lock);
+        FileLock::acquire(&path, Duration::from_millis(0)).unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_created_empty() {
+        let dir = TempDir::new("lock-missing");
+        let path = dir.path().join("2020-02.data");
+
+        FileLock::acquire(&path, Duration::from_millis(0)).unwrap();
+
+        assert_eq!(dir.read("2020-02.data"), "");
+    }
+
+    #[test]
+    fn waits_for_lock_to_be_released() {
+        let dir = TempDir::new("lock-wait");
+        let path = dir.path().join("2020-02.data");
+        let lock = FileLock::acquire(&path, Duration::from_millis(0)).unwrap();
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(lock);
+        });
+
+        let result = FileLock::acquire(&path, Duration::from_secs(5));
+        release.join().unwrap();
+        assert_eq!(result.is_ok(), true, "{:?}", result);
+    }
+
+    #[test]
+    fn replaced_files_stay_locked() {
+        let dir = TempDir::new("lock-replace");
+        let path = dir.write("2020-02.data", "old\n");
+        let mut lock = FileLock::acquire(&path, Duration::from_millis(0)).unwrap();
+
+        lock.replace("new\n").unwrap();
+
+        assert_eq!(dir.read("2020-02.data"), "new\n");
+        assert_eq!(dir.path().join(".2020-02.data.tmp").exists(), false);
+        let result = FileLock::acquire(&path, Duration::from_millis(50));
+        assert_eq!(
+            matches!(result, Err(DatabaseError::Locked { .. })),
+            true,
+            "{:?}",
+            result
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn waiting_moves_on_to_the_replacing_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TempDir::new("lock-follow");
+        let path = dir.write("2020-02.data", "old\n");
+        let mut lock = FileLock::acquire(&path, Duration::from_millis(0)).unwrap();
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            lock.replace("new\n").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            drop(lock);
+        });
+
+        let waiting = FileLock::acquire(&path, Duration::from_secs(5)).unwrap();
+        writer.join().unwrap();
+        assert_eq!(
+            waiting.file.metadata().unwrap().ino(),
+            fs::metadata(&path).unwrap().ino()
+        );
+        assert_eq!(dir.read("2020-02.data"), "new\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn conflicts_with_the_record_locks_of_timew() {
+        use std::os::unix::io::AsRawFd;
+
+        let dir = TempDir::new("lock-timew");
+        let path = dir.write("2020-02.data", "");
+
+        // what libshared's File::lock does for timew
+        let timew = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as libc::c_short;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+        assert_eq!(
+            unsafe { libc::fcntl(timew.as_raw_fd(), libc::F_SETLK, &lock) },
+            0
+        );
+
+        let result = FileLock::acquire(&path, Duration::from_millis(50));
+        assert_eq!(
+            matches!(result, Err(DatabaseError::Locked { .. })),
+            true,
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn locks_are_taken_once_per_path() {
+        let dir = TempDir::new("lock-many");
+        let first = dir.path().join("2020-01.data");
+        let second = dir.path().join("2020-02.data");
+
+        let mut locks = FileLocks::acquire(
+            vec![second.clone(), first.clone(), second.clone()],
+            Duration::from_millis(0),
+        )
+        .unwrap();
+        locks.replace(&second, "new\n").unwrap();
+
+        assert_eq!(dir.read("2020-02.data"), "new\n");
+        for path in [&first, &second] {
+            let result = FileLock::acquire(path, Duration::from_millis(0));
+            assert_eq!(
+                matches!(result, Err(DatabaseError::Locked { .. })),
+                true,
+                "{:?}",
+                result
+            );
+        }
+    }
+}
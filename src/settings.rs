@@ -0,0 +1,332 @@
+//! Typed access to the settings timewarrior itself understands
+
+use crate::{Config, ConfigError};
+use chrono::Weekday;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// The reports timewarrior ships, with the range each shows by default
+const REPORT_RANGES: [(&str, RangeHint); 5] = [
+    ("day", RangeHint::Today),
+    ("week", RangeHint::Week),
+    ("month", RangeHint::Month),
+    ("summary", RangeHint::Today),
+    ("gaps", RangeHint::Today),
+];
+
+/// A named range like `:week`, as used by `reports.<report>.range`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeHint {
+    All,
+    Today,
+    Yesterday,
+    Week,
+    LastWeek,
+    Fortnight,
+    LastFortnight,
+    Month,
+    LastMonth,
+    Quarter,
+    LastQuarter,
+    Year,
+    LastYear,
+    /// The latest of these weekdays, like `:monday`
+    Weekday(Weekday),
+}
+
+impl RangeHint {
+    const NAMES: [(&'static str, RangeHint); 20] = [
+        ("all", RangeHint::All),
+        ("day", RangeHint::Today),
+        ("yesterday", RangeHint::Yesterday),
+        ("week", RangeHint::Week),
+        ("lastweek", RangeHint::LastWeek),
+        ("fortnight", RangeHint::Fortnight),
+        ("lastfortnight", RangeHint::LastFortnight),
+        ("month", RangeHint::Month),
+        ("lastmonth", RangeHint::LastMonth),
+        ("quarter", RangeHint::Quarter),
+        ("lastquarter", RangeHint::LastQuarter),
+        ("year", RangeHint::Year),
+        ("lastyear", RangeHint::LastYear),
+        ("monday", RangeHint::Weekday(Weekday::Mon)),
+        ("tuesday", RangeHint::Weekday(Weekday::Tue)),
+        ("wednesday", RangeHint::Weekday(Weekday::Wed)),
+        ("thursday", RangeHint::Weekday(Weekday::Thu)),
+        ("friday", RangeHint::Weekday(Weekday::Fri)),
+        ("saturday", RangeHint::Weekday(Weekday::Sat)),
+        ("sunday", RangeHint::Weekday(Weekday::Sun)),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        RangeHint::NAMES
+            .iter()
+            .find(|(_, hint)| hint == self)
+            .map(|(name, _)| *name)
+            .unwrap_or("all")
+    }
+}
+
+/// A name that is not one of the range hints
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownRangeHint(pub String);
+
+impl fmt::Display for UnknownRangeHint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown range hint {:?}", self.0)
+    }
+}
+
+impl Error for UnknownRangeHint {}
+
+impl FromStr for RangeHint {
+    type Err = UnknownRangeHint;
+
+    /// Parses a hint with or without its leading colon, `today` is `day`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let name = value.strip_prefix(':').unwrap_or(value).to_lowercase();
+        if name == "today" {
+            return Ok(RangeHint::Today);
+        }
+        RangeHint::NAMES
+            .iter()
+            .find(|(hint, _)| *hint == name)
+            .map(|(_, hint)| *hint)
+            .ok_or_else(|| UnknownRangeHint(value.to_owned()))
+    }
+}
+
+impl fmt::Display for RangeHint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, ":{}", self.name())
+    }
+}
+
+impl Config {
+    /// A boolean setting, read like timewarrior reads `on`, `yes`, `1`
+    /// and `true` or their opposites
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        let value = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match value.to_lowercase().as_str() {
+            "on" | "yes" | "y" | "true" | "1" => Ok(Some(true)),
+            "off" | "no" | "n" | "false" | "0" | "" => Ok(Some(false)),
+            _ => Err(invalid(key, value, "on or off")),
+        }
+    }
+
+    /// A whole number setting
+    pub fn get_integer(&self, key: &str) -> Result<Option<i64>, ConfigError> {
+        match self.get(key) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid(key, value, "a whole number")),
+            None => Ok(None),
+        }
+    }
+
+    /// `verbose`, on by default
+    pub fn verbose(&self) -> Result<bool, ConfigError> {
+        Ok(self.get_bool("verbose")?.unwrap_or(true))
+    }
+
+    /// `confirmation`, whether to ask before risky changes, on by default
+    pub fn confirmation(&self) -> Result<bool, ConfigError> {
+        Ok(self.get_bool("confirmation")?.unwrap_or(true))
+    }
+
+    /// `debug`, off by default
+    pub fn debug(&self) -> Result<bool, ConfigError> {
+        Ok(self.get_bool("debug")?.unwrap_or(false))
+    }
+
+    /// `journal.size`, the number of transactions `undo.data` keeps
+    ///
+    /// `None` keeps all of them, the default, which timewarrior writes as
+    /// `-1`. Zero turns the journal off.
+    pub fn journal_size(&self) -> Result<Option<u64>, ConfigError> {
+        match self.get_integer("journal.size")? {
+            None | Some(-1) => Ok(None),
+            Some(size) if size >= 0 => Ok(Some(size as u64)),
+            Some(_) => Err(invalid(
+                "journal.size",
+                self.get("journal.size").unwrap_or_default(),
+                "-1 or more",
+            )),
+        }
+    }
+
+    /// `weekstart`, the first day of a week in reports, Monday by default
+    pub fn week_start(&self) -> Result<Weekday, ConfigError> {
+        match self.get("weekstart") {
+            None => Ok(Weekday::Mon),
+            Some(value) => match value.to_lowercase().as_str() {
+                "monday" => Ok(Weekday::Mon),
+                "sunday" => Ok(Weekday::Sun),
+                _ => Err(invalid("weekstart", value, "monday or sunday")),
+            },
+        }
+    }
+
+    /// `reports.<report>.range`, the range a report covers when none is
+    /// given
+    ///
+    /// The built-in reports default to the range they are named after,
+    /// `summary` and `gaps` to today and any other report to all data.
+    pub fn report_range(&self, report: &str) -> Result<RangeHint, ConfigError> {
+        let key = format!("reports.{}.range", report);
+        match self.get(&key) {
+            Some(value) if !value.is_empty() => value
+                .parse()
+                .map_err(|_| invalid(&key, value, "a range hint like :week")),
+            _ => Ok(REPORT_RANGES
+                .iter()
+                .find(|(name, _)| *name == report)
+                .map(|(_, hint)| *hint)
+                .unwrap_or(RangeHint::All)),
+        }
+    }
+
+    /// `reports.<report>.totals`, whether a report adds a totals column,
+    /// off by default
+    pub fn report_totals(&self, report: &str) -> Result<bool, ConfigError> {
+        Ok(self
+            .get_bool(&format!("reports.{}.totals", report))?
+            .unwrap_or(false))
+    }
+
+    /// Checks every setting that has a typed accessor, returning all invalid
+    /// values instead of only the first
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |result: Result<(), ConfigError>| {
+            if let Err(error) = result {
+                errors.push(error);
+            }
+        };
+        check(self.verbose().map(drop));
+        check(self.confirmation().map(drop));
+        check(self.debug().map(drop));
+        check(self.journal_size().map(drop));
+        check(self.week_start().map(drop));
+
+        let mut reports: Vec<&str> = self
+            .section("reports")
+            .filter_map(|(key, _)| key.split_once('.').map(|(report, _)| report))
+            .collect();
+        reports.dedup();
+        for report in reports {
+            check(self.report_range(report).map(drop));
+            check(self.report_totals(report).map(drop));
+        }
+        errors
+    }
+}
+
+fn invalid(key: &str, value: &str, expected: &'static str) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_timewarrior_defaults() {
+        let config = Config::default();
+
+        assert_eq!(config.verbose(), Ok(true));
+        assert_eq!(config.confirmation(), Ok(true));
+        assert_eq!(config.debug(), Ok(false));
+        assert_eq!(config.journal_size(), Ok(None));
+        assert_eq!(config.week_start(), Ok(Weekday::Mon));
+        assert_eq!(config.report_range("week"), Ok(RangeHint::Week));
+        assert_eq!(config.report_range("summary"), Ok(RangeHint::Today));
+        assert_eq!(config.report_range("my-extension"), Ok(RangeHint::All));
+        assert_eq!(config.report_totals("day"), Ok(false));
+        assert_eq!(config.validate(), vec![]);
+    }
+
+    #[test]
+    fn reads_configured_values() {
+        let config: Config = "verbose = no
+confirmation = Off
+debug = 1
+journal.size = 50
+weekstart = Sunday
+reports:
+  week:
+    range = :lastweek
+    totals = yes
+  summary.range = month
+  day.range = :Monday
+"
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.verbose(), Ok(false));
+        assert_eq!(config.confirmation(), Ok(false));
+        assert_eq!(config.debug(), Ok(true));
+        assert_eq!(config.journal_size(), Ok(Some(50)));
+        assert_eq!(config.week_start(), Ok(Weekday::Sun));
+        assert_eq!(config.report_range("week"), Ok(RangeHint::LastWeek));
+        assert_eq!(config.report_range("summary"), Ok(RangeHint::Month));
+        assert_eq!(
+            config.report_range("day"),
+            Ok(RangeHint::Weekday(Weekday::Mon))
+        );
+        assert_eq!(config.report_totals("week"), Ok(true));
+        assert_eq!(
+            config.report_range("week").unwrap().to_string(),
+            ":lastweek"
+        );
+        assert_eq!(config.report_range("day").unwrap().to_string(), ":monday");
+        assert_eq!(
+            ":someday".parse::<RangeHint>(),
+            Err(UnknownRangeHint(":someday".to_owned()))
+        );
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let config: Config = "verbose = maybe
+journal.size = -5
+weekstart = wednesday
+reports.day.range = :someday
+reports.day.totals = sometimes
+"
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            config.verbose(),
+            Err(ConfigError::InvalidValue {
+                key: "verbose".to_owned(),
+                value: "maybe".to_owned(),
+                expected: "on or off",
+            })
+        );
+        assert_eq!(
+            config
+                .validate()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "verbose = \"maybe\" is invalid, expected on or off",
+                "journal.size = \"-5\" is invalid, expected -1 or more",
+                "weekstart = \"wednesday\" is invalid, expected monday or sunday",
+                "reports.day.range = \":someday\" is invalid, expected a range hint like :week",
+                "reports.day.totals = \"sometimes\" is invalid, expected on or off",
+            ]
+        );
+    }
+}
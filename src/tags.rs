@@ -0,0 +1,406 @@
+use crate::json::{self, Value};
+use crate::lock::DEFAULT_LOCK_TIMEOUT;
+use crate::{DatabaseError, FileLock, TimeWarriorLine};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// What timewarrior knows about a tag
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TagInfo {
+    /// the number of intervals using the tag
+    pub count: u64,
+    pub description: Option<String>,
+    pub color: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagsDataError {
+    /// The file is not valid JSON
+    Json { offset: usize, message: String },
+    /// The JSON does not map tags to objects with a numeric `count`
+    InvalidEntry { tag: String, message: String },
+}
+
+impl fmt::Display for TagsDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagsDataError::Json { offset, message } => {
+                write!(f, "invalid JSON at byte {}: {}", offset, message)
+            }
+            TagsDataError::InvalidEntry { tag, message } => {
+                write!(f, "invalid entry for tag {:?}: {}", tag, message)
+            }
+        }
+    }
+}
+
+impl Error for TagsDataError {}
+
+/// A tag whose count in `tags.data` does not match the intervals
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagDrift {
+    pub tag: String,
+    pub recorded: u64,
+    pub actual: u64,
+}
+
+/// The contents of `tags.data`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TagsData {
+    tags: BTreeMap<String, TagInfo>,
+}
+
+impl TagsData {
+    /// Reads `tags.data`, a missing file has no tags
+    pub fn load(path: &Path) -> Result<TagsData, DatabaseError> {
+        match fs::read_to_string(path) {
+            Ok(content) => content.parse().map_err(|error| DatabaseError::TagsData {
+                path: path.to_owned(),
+                error,
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(TagsData::default()),
+            Err(error) => Err(DatabaseError::io(path)(error)),
+        }
+    }
+
+    /// Replaces `tags.data` atomically while holding its lock, see
+    /// `FileLock`
+    pub fn save(&self, path: &Path) -> Result<(), DatabaseError> {
+        FileLock::acquire(path, DEFAULT_LOCK_TIMEOUT)?.replace(&self.to_string())
+    }
+
+    /// Counts the tags of `intervals`
+    pub fn from_intervals<'a, I>(intervals: I) -> TagsData
+    where
+        I: IntoIterator<Item = &'a TimeWarriorLine>,
+    {
+        let mut data = TagsData::default();
+        for (tag, count) in count_tags(intervals) {
+            data.tags.insert(
+                tag,
+                TagInfo {
+                    count,
+                    ..TagInfo::default()
+                },
+            );
+        }
+        data
+    }
+
+    /// Recounts the tags of `intervals`, keeping descriptions and colours
+    ///
+    /// Unused tags are dropped unless they have a description or colour.
+    pub fn rebuild<'a, I>(&mut self, intervals: I)
+    where
+        I: IntoIterator<Item = &'a TimeWarriorLine>,
+    {
+        let mut counts = count_tags(intervals);
+        self.tags.retain(|tag, info| {
+            info.count = counts.remove(tag).unwrap_or(0);
+            info.count > 0 || info.description.is_some() || info.color.is_some()
+        });
+        for (tag, count) in counts {
+            self.tags.insert(
+                tag,
+                TagInfo {
+                    count,
+                    ..TagInfo::default()
+                },
+            );
+        }
+    }
+
+    /// Tags whose recorded count differs from their use in `intervals`,
+    /// including used tags missing from the file
+    pub fn check<'a, I>(&self, intervals: I) -> Vec<TagDrift>
+    where
+        I: IntoIterator<Item = &'a TimeWarriorLine>,
+    {
+        let mut counts = count_tags(intervals);
+        let mut drift = Vec::new();
+        for (tag, info) in &self.tags {
+            let actual = counts.remove(tag).unwrap_or(0);
+            if actual != info.count {
+                drift.push(TagDrift {
+                    tag: tag.clone(),
+                    recorded: info.count,
+                    actual,
+                });
+            }
+        }
+        for (tag, actual) in counts {
+            drift.push(TagDrift {
+                tag,
+                recorded: 0,
+                actual,
+            });
+        }
+        drift.sort_by(|a, b| a.tag.cmp(&b.tag));
+        drift
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&TagInfo> {
+        self.tags.get(tag)
+    }
+
+    pub fn get_mut(&mut self, tag: &str) -> Option<&mut TagInfo> {
+        self.tags.get_mut(tag)
+    }
+
+    pub fn insert<S: Into<String>>(&mut self, tag: S, info: TagInfo) -> Option<TagInfo> {
+        self.tags.insert(tag.into(), info)
+    }
+
+    pub fn remove(&mut self, tag: &str) -> Option<TagInfo> {
+        self.tags.remove(tag)
+    }
+
+    /// The tags in alphabetical order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TagInfo)> {
+        self.tags.iter().map(|(tag, info)| (tag.as_str(), info))
+    }
+}
+
+fn count_tags<'a, I>(intervals: I) -> BTreeMap<String, u64>
+where
+    I: IntoIterator<Item = &'a TimeWarriorLine>,
+{
+    let mut counts = BTreeMap::new();
+    for interval in intervals {
+        for tag in interval.tags() {
+            *counts.entry(tag.clone()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+impl FromStr for TagsData {
+    type Err = TagsDataError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let members = match json::parse(content) {
+            Ok(Value::Object(members)) => members,
+            Ok(_) => {
+                return Err(TagsDataError::Json {
+                    offset: 0,
+                    message: "expected an object".to_owned(),
+                })
+            }
+            Err(error) => {
+                return Err(TagsDataError::Json {
+                    offset: error.offset,
+                    message: error.message.to_owned(),
+                })
+            }
+        };
+
+        let mut data = TagsData::default();
+        for (tag, value) in members {
+            let invalid = |message: &str| TagsDataError::InvalidEntry {
+                tag: tag.clone(),
+                message: message.to_owned(),
+            };
+            let fields = match value {
+                Value::Object(fields) => fields,
+                _ => return Err(invalid("expected an object")),
+            };
+
+            let mut info = TagInfo::default();
+            for (key, value) in fields {
+                match (key.as_str(), value) {
+                    ("count", Value::Number(count)) if count >= 0.0 && count.fract() == 0.0 => {
+                        info.count = count as u64
+                    }
+                    ("count", _) => return Err(invalid("count is not a whole number")),
+                    ("description", Value::String(text)) => info.description = Some(text),
+                    ("color", Value::String(text)) => info.color = Some(text),
+                    ("description", _) | ("color", _) => {
+                        return Err(invalid(&format!("{} is not a string", key)))
+                    }
+                    // fields of newer timewarrior versions
+                    _ => (),
+                }
+            }
+            data.tags.insert(tag, info);
+        }
+        Ok(data)
+    }
+}
+
+impl fmt::Display for TagsData {
+    /// Writes the JSON layout timewarrior uses, one tag per line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (index, (tag, info)) in self.tags.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(
+                f,
+                "{}\n  {}:{{\"count\":{}",
+                separator,
+                json::quote(tag),
+                info.count
+            )?;
+            if let Some(description) = &info.description {
+                write!(f, ",\"description\":{}", json::quote(description))?;
+            }
+            if let Some(color) = &info.color {
+                write!(f, ",\"color\":{}", json::quote(color))?;
+            }
+            write!(f, "}}")?;
+        }
+        if !self.tags.is_empty() {
+            writeln!(f)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{interval, TempDir};
+
+    fn intervals() -> Vec<TimeWarriorLine> {
+        vec![
+            "inc 20200201T080000Z - 20200201T090000Z # work \"client a\"",
+            "inc 20200202T080000Z - 20200202T090000Z # work",
+            "inc 20200203T080000Z # lunch",
+        ]
+        .into_iter()
+        .map(interval)
+        .collect()
+    }
+
+    #[test]
+    fn parses_tags_data() {
+        let data: TagsData = r#"{
+  "work":{"count":2,"description":"paid","color":"red"},
+  "lunch":{"count":1,"future":true}
+}"#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            data.get("work"),
+            Some(&TagInfo {
+                count: 2,
+                description: Some("paid".to_owned()),
+                color: Some("red".to_owned()),
+            })
+        );
+        assert_eq!(data.get("lunch").map(|info| info.count), Some(1));
+        assert_eq!(data.iter().count(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert_eq!(
+            r#"{"work":{"count":-1}}"#.parse::<TagsData>(),
+            Err(TagsDataError::InvalidEntry {
+                tag: "work".to_owned(),
+                message: "count is not a whole number".to_owned(),
+            })
+        );
+        assert_eq!(
+            r#"{"work":3}"#.parse::<TagsData>(),
+            Err(TagsDataError::InvalidEntry {
+                tag: "work".to_owned(),
+                message: "expected an object".to_owned(),
+            })
+        );
+        assert_eq!(
+            "[]".parse::<TagsData>(),
+            Err(TagsDataError::Json {
+                offset: 0,
+                message: "expected an object".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn builds_counts_from_intervals() {
+        let data = TagsData::from_intervals(&intervals());
+
+        assert_eq!(
+            data.to_string(),
+            "{\n  \"client a\":{\"count\":1},\n  \"lunch\":{\"count\":1},\n  \"work\":{\"count\":2}\n}\n"
+        );
+    }
+
+    #[test]
+    fn rebuild_keeps_descriptions() {
+        let mut data: TagsData = r#"{
+  "work":{"count":7,"description":"paid"},
+  "gone":{"count":3},
+  "styled":{"count":1,"color":"blue"}
+}"#
+        .parse()
+        .unwrap();
+
+        data.rebuild(&intervals());
+
+        assert_eq!(
+            data.iter()
+                .map(|(tag, info)| (tag, info.count))
+                .collect::<Vec<_>>(),
+            vec![("client a", 1), ("lunch", 1), ("styled", 0), ("work", 2)]
+        );
+        assert_eq!(
+            data.get("work").unwrap().description,
+            Some("paid".to_owned())
+        );
+    }
+
+    #[test]
+    fn check_reports_drifting_counts() {
+        let data: TagsData = r#"{"work":{"count":5},"lunch":{"count":1},"gone":{"count":2}}"#
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            data.check(&intervals()),
+            vec![
+                TagDrift {
+                    tag: "client a".to_owned(),
+                    recorded: 0,
+                    actual: 1,
+                },
+                TagDrift {
+                    tag: "gone".to_owned(),
+                    recorded: 2,
+                    actual: 0,
+                },
+                TagDrift {
+                    tag: "work".to_owned(),
+                    recorded: 5,
+                    actual: 2,
+                },
+            ]
+        );
+        assert_eq!(
+            TagsData::from_intervals(&intervals()).check(&intervals()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new("tags-data");
+        let path = dir.path().join("tags.data");
+        let mut data = TagsData::from_intervals(&intervals());
+        data.get_mut("work").unwrap().description = Some("say \"hi\"".to_owned());
+
+        data.save(&path).unwrap();
+
+        assert_eq!(TagsData::load(&path).unwrap(), data);
+        assert_eq!(
+            TagsData::load(&dir.path().join("missing.data")).unwrap(),
+            TagsData::default()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use chrono::prelude::*;
+use std::error::Error;
+use std::fmt;
+
+/// Parses a timewarrior timestamp in the fixed `YYYYMMDDTHHMMSSZ` layout
+///
+/// Only UTC timestamps ending in `Z` are valid, out of range fields like
+/// month 13 or February 30th are rejected.
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    let bytes = timestamp.as_bytes();
+    if bytes.len() != 16 || bytes[8] != b'T' || bytes[15] != b'Z' {
+        return None;
+    }
+
+    let year = digits(&bytes[0..4])?;
+    let month = digits(&bytes[4..6])?;
+    let day = digits(&bytes[6..8])?;
+    let hour = digits(&bytes[9..11])?;
+    let minute = digits(&bytes[11..13])?;
+    let second = digits(&bytes[13..15])?;
+
+    let date = NaiveDate::from_ymd_opt(year as i32, month, day)?;
+    let time = NaiveTime::from_hms_opt(hour, minute, second)?;
+    Some(Utc.from_utc_datetime(&date.and_time(time)))
+}
+
+/// Formats a timestamp the way timewarrior writes it to its data files
+///
+/// Only timestamps in the years 0 to 9999 fit the fixed layout and can be
+/// read back with `parse_timestamp`, see `is_representable`.
+pub fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Whether the year of `timestamp` fits the four digits of the data files
+pub(crate) fn is_representable(timestamp: &DateTime<Utc>) -> bool {
+    (0..=9999).contains(&timestamp.year())
+}
+
+/// A timestamp outside of the years 0 to 9999 the data files can hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestampOutOfRange(pub DateTime<Utc>);
+
+impl fmt::Display for TimestampOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timestamp {} is outside of the years 0 to 9999",
+            self.0.to_rfc3339()
+        )
+    }
+}
+
+impl Error for TimestampOutOfRange {}
+
+fn digits(bytes: &[u8]) -> Option<u32> {
+    bytes.iter().try_fold(0, |value, byte| match byte {
+        b'0'..=b'9' => Some(value * 10 + u32::from(byte - b'0')),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+
+    fn parse_with_chrono(timestamp: &str) -> Option<DateTime<Utc>> {
+        NaiveDateTime::parse_from_str(timestamp, "%Y%m%dT%H%M%SZ")
+            .ok()
+            .map(|naive| Utc.from_utc_datetime(&naive))
+    }
+
+    #[test]
+    fn parses_valid_timestamps() {
+        assert_eq!(
+            parse_timestamp("20001011T133055Z"),
+            Some(Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap())
+        );
+        assert_eq!(
+            parse_timestamp("20000229T000000Z"),
+            Some(Utc.with_ymd_and_hms(2000, 2, 29, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_timestamp("99991231T235959Z"),
+            Some(Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        let timestamps = vec![
+            "20001311T133055Z",
+            "20000011T133055Z",
+            "20001000T133055Z",
+            "20001032T133055Z",
+            "19000229T133055Z",
+            "20001011T243055Z",
+            "20001011T136055Z",
+            "20001011T133060Z",
+        ];
+
+        for timestamp in timestamps {
+            assert_eq!(parse_timestamp(timestamp), None, "{:?}", timestamp);
+        }
+    }
+
+    #[test]
+    fn rejects_other_layouts() {
+        let timestamps = vec![
+            "",
+            "20001011T133055",
+            "20001011T133055CEST",
+            "20001011 133055Z",
+            "2000-10-11T13:30Z",
+            "20001011T13305+Z",
+            "20001011T1330５Z",
+            "+2001011T133055Z",
+        ];
+
+        for timestamp in timestamps {
+            assert_eq!(parse_timestamp(timestamp), None, "{:?}", timestamp);
+        }
+    }
+
+    #[test]
+    fn agrees_with_chrono_format_parser() {
+        let timestamps = vec![
+            "20001011T133055Z",
+            "19700101T000000Z",
+            "20240229T235959Z",
+            "20230229T120000Z",
+            "20001011T133055CEST",
+            "20001011T250000Z",
+        ];
+
+        for timestamp in timestamps {
+            assert_eq!(
+                parse_timestamp(timestamp),
+                parse_with_chrono(timestamp),
+                "{:?}",
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn only_four_digit_years_are_representable() {
+        let last = Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap();
+        let first = Utc.with_ymd_and_hms(0, 1, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(is_representable(&last), true);
+        assert_eq!(parse_timestamp(&format_timestamp(&last)), Some(last));
+        assert_eq!(is_representable(&first), true);
+        assert_eq!(parse_timestamp(&format_timestamp(&first)), Some(first));
+
+        let after = last + chrono::Duration::seconds(1);
+        let before = first - chrono::Duration::seconds(1);
+        assert_eq!(is_representable(&after), false);
+        assert_eq!(parse_timestamp(&format_timestamp(&after)), None);
+        assert_eq!(is_representable(&before), false);
+        assert_eq!(parse_timestamp(&format_timestamp(&before)), None);
+    }
+
+    #[test]
+    fn format_is_inverse_of_parse() {
+        let timestamp = Utc.with_ymd_and_hms(2000, 10, 11, 13, 30, 55).unwrap();
+
+        assert_eq!(format_timestamp(&timestamp), "20001011T133055Z");
+        assert_eq!(
+            parse_timestamp(&format_timestamp(&timestamp)),
+            Some(timestamp)
+        );
+    }
+}
@@ -0,0 +1,528 @@
+use crate::json::{self, Value};
+use crate::{format_timestamp, parse_timestamp, DatabaseError, TimeWarriorLine};
+use chrono::prelude::*;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// One change recorded in `undo.data`
+///
+/// Timewarrior writes interval payloads as JSON objects like
+/// `{"start":"20200201T080000Z","end":"20200201T090000Z","tags":["work"]}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UndoAction {
+    /// An interval was added (no `before`), deleted (no `after`) or changed
+    Interval {
+        before: Option<TimeWarriorLine>,
+        after: Option<TimeWarriorLine>,
+    },
+    /// Any other kind of change, like `config`, with its raw payloads
+    Other {
+        kind: String,
+        before: String,
+        after: String,
+    },
+}
+
+/// A `txn:` block, the changes a single timewarrior command made
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Transaction {
+    pub actions: Vec<UndoAction>,
+}
+
+impl fmt::Display for Transaction {
+    /// Writes the `txn:` block the way timewarrior does
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "txn:")?;
+        for action in &self.actions {
+            match action {
+                UndoAction::Interval { before, after } => {
+                    let payload = |line: &Option<TimeWarriorLine>| match line {
+                        Some(line) => interval_json(line),
+                        None => String::new(),
+                    };
+                    writeln!(f, "  type: interval")?;
+                    writeln!(f, "  before: {}", payload(before))?;
+                    writeln!(f, "  after: {}", payload(after))?;
+                }
+                UndoAction::Other {
+                    kind,
+                    before,
+                    after,
+                } => {
+                    writeln!(f, "  type: {}", kind)?;
+                    writeln!(f, "  before: {}", before)?;
+                    writeln!(f, "  after: {}", after)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JournalError {
+    /// A line that is neither `txn:`, `type:`, `before:` nor `after:`
+    UnexpectedLine { line_number: usize, line: String },
+    /// The payload of an interval action is not a valid interval object
+    Interval { line_number: usize, message: String },
+    /// Replaying the journal found an interval missing from the database
+    Diverged {
+        transaction: usize,
+        interval: TimeWarriorLine,
+    },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JournalError::UnexpectedLine { line_number, line } => {
+                write!(f, "line {}: unexpected {:?}", line_number, line)
+            }
+            JournalError::Interval {
+                line_number,
+                message,
+            } => write!(f, "line {}: invalid interval: {}", line_number, message),
+            JournalError::Diverged {
+                transaction,
+                interval,
+            } => write!(
+                f,
+                "transaction {} changed an interval that is not in the database: {}",
+                transaction, interval
+            ),
+        }
+    }
+}
+
+impl Error for JournalError {}
+
+/// The transactions of an `undo.data` file, oldest first
+///
+/// The journal only records what changed, not when or by whom.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Journal {
+    transactions: Vec<Transaction>,
+}
+
+impl Journal {
+    /// Reads `undo.data`, a missing file is an empty journal
+    pub fn load(path: &Path) -> Result<Journal, DatabaseError> {
+        match fs::read_to_string(path) {
+            Ok(content) => content.parse().map_err(|error| DatabaseError::Journal {
+                path: path.to_owned(),
+                error: Box::new(error),
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Journal::default()),
+            Err(error) => Err(DatabaseError::io(path)(error)),
+        }
+    }
+
+    /// Appends a transaction to `undo.data`, creating the file if needed
+    pub fn append(path: &Path, transaction: &Transaction) -> Result<(), DatabaseError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(transaction.to_string().as_bytes()))
+            .map_err(DatabaseError::io(path))
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn push(&mut self, transaction: Transaction) {
+        self.transactions.push(transaction);
+    }
+
+    /// Removes the latest transaction
+    pub fn pop(&mut self) -> Option<Transaction> {
+        self.transactions.pop()
+    }
+
+    /// Drops all but the latest `count` transactions, like timewarrior does
+    /// for `journal.size`
+    pub fn keep_latest(&mut self, count: usize) {
+        let excess = self.transactions.len().saturating_sub(count);
+        self.transactions.drain(..excess);
+    }
+
+    /// The intervals as they were after the first `applied` transactions,
+    /// found by undoing the later ones on top of `current`
+    pub fn state_at(
+        &self,
+        current: &[TimeWarriorLine],
+        applied: usize,
+    ) -> Result<Vec<TimeWarriorLine>, JournalError> {
+        let mut state = current.to_vec();
+        for (index, transaction) in self.transactions.iter().enumerate().skip(applied).rev() {
+            for action in transaction.actions.iter().rev() {
+                if let UndoAction::Interval { before, after } = action {
+                    if let Some(after) = after {
+                        match state.iter().position(|interval| interval == after) {
+                            Some(position) => {
+                                state.remove(position);
+                            }
+                            None => {
+                                return Err(JournalError::Diverged {
+                                    transaction: index,
+                                    interval: after.clone(),
+                                });
+                            }
+                        }
+                    }
+                    if let Some(before) = before {
+                        state.push(before.clone());
+                    }
+                }
+            }
+        }
+        state.sort_by_key(TimeWarriorLine::from);
+        Ok(state)
+    }
+}
+
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for transaction in &self.transactions {
+            write!(f, "{}", transaction)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Journal {
+    type Err = JournalError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut transactions: Vec<Transaction> = Vec::new();
+        // kind, before and after of the action being read
+        let mut action: Option<(String, String, String)> = None;
+
+        for (index, line) in content.lines().enumerate() {
+            let line_number = index + 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            let unexpected = || JournalError::UnexpectedLine {
+                line_number,
+                line: line.to_owned(),
+            };
+            let (key, value) = match trimmed.find(':') {
+                Some(colon) => (&trimmed[..colon], trimmed[colon + 1..].trim()),
+                None => return Err(unexpected()),
+            };
+
+            match key {
+                "txn" => {
+                    finish_action(&mut transactions, action.take());
+                    transactions.push(Transaction::default());
+                }
+                "type" if !transactions.is_empty() => {
+                    finish_action(&mut transactions, action.take());
+                    action = Some((value.to_owned(), String::new(), String::new()));
+                }
+                "before" | "after" => {
+                    let (kind, before, after) = action.as_mut().ok_or_else(unexpected)?;
+                    if kind == "interval" && !value.is_empty() {
+                        if let Err(message) = parse_interval(value) {
+                            return Err(JournalError::Interval {
+                                line_number,
+                                message,
+                            });
+                        }
+                    }
+                    if key == "before" {
+                        *before = value.to_owned();
+                    } else {
+                        *after = value.to_owned();
+                    }
+                }
+                _ => return Err(unexpected()),
+            }
+        }
+        finish_action(&mut transactions, action);
+
+        Ok(Journal { transactions })
+    }
+}
+
+fn finish_action(transactions: &mut [Transaction], action: Option<(String, String, String)>) {
+    let (kind, before, after) = match action {
+        Some(action) => action,
+        None => return,
+    };
+    let parse = |payload: &str| parse_interval(payload).ok();
+
+    let action = if kind == "interval" {
+        UndoAction::Interval {
+            before: parse(&before),
+            after: parse(&after),
+        }
+    } else {
+        UndoAction::Other {
+            kind,
+            before,
+            after,
+        }
+    };
+    if let Some(transaction) = transactions.last_mut() {
+        transaction.actions.push(action);
+    }
+}
+
+/// Reads the JSON object timewarrior writes for an interval, ignoring
+/// fields like `id` that only matter to its reports
+fn parse_interval(payload: &str) -> Result<TimeWarriorLine, String> {
+    let members = match json::parse(payload) {
+        Ok(Value::Object(members)) => members,
+        Ok(_) => return Err("expected an object".to_owned()),
+        Err(error) => {
+            return Err(format!(
+                "invalid JSON at byte {}: {}",
+                error.offset, error.message
+            ))
+        }
+    };
+
+    let timestamp = |key: &str, text: &str| {
+        parse_timestamp(text).ok_or_else(|| format!("{} is not a timestamp: {:?}", key, text))
+    };
+    let mut start: Option<DateTime<Utc>> = None;
+    let mut end = None;
+    let mut tags = Vec::new();
+    let mut annotation = None;
+    for (key, value) in members {
+        match (key.as_str(), value) {
+            ("start", Value::String(text)) => start = Some(timestamp("start", &text)?),
+            ("end", Value::String(text)) => end = Some(timestamp("end", &text)?),
+            ("tags", Value::Array(values)) => {
+                for value in values {
+                    match value {
+                        Value::String(tag) => tags.push(tag),
+                        _ => return Err("tags is not a list of strings".to_owned()),
+                    }
+                }
+            }
+            ("annotation", Value::String(text)) => annotation = Some(text),
+            ("start", _) | ("end", _) | ("tags", _) | ("annotation", _) => {
+                return Err(format!("{} is not a string", key))
+            }
+            _ => (),
+        }
+    }
+
+    let mut builder = TimeWarriorLine::builder(start.ok_or("start is missing")?).tags(tags);
+    if let Some(end) = end {
+        builder = builder.until(end);
+    }
+    if let Some(annotation) = annotation {
+        builder = builder.annotation(annotation);
+    }
+    builder.build().map_err(|error| error.to_string())
+}
+
+/// Writes an interval as the JSON object timewarrior uses in `undo.data`
+fn interval_json(interval: &TimeWarriorLine) -> String {
+    let mut out = format!("{{\"start\":\"{}\"", format_timestamp(&interval.from()));
+    if let Some(until) = interval.until() {
+        out.push_str(&format!(",\"end\":\"{}\"", format_timestamp(&until)));
+    }
+    if !interval.tags().is_empty() {
+        let tags: Vec<String> = interval.tags().iter().map(|tag| json::quote(tag)).collect();
+        out.push_str(&format!(",\"tags\":[{}]", tags.join(",")));
+    }
+    if let Some(annotation) = interval.annotation() {
+        out.push_str(&format!(",\"annotation\":{}", json::quote(annotation)));
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::{interval, TempDir};
+
+    const JOURNAL: &str = r#"txn:
+  type: interval
+  before:
+  after: {"start":"20200201T080000Z","end":"20200201T090000Z","tags":["first"]}
+txn:
+  type: interval
+  before: {"start":"20200201T080000Z","end":"20200201T090000Z","tags":["first"]}
+  after: {"start":"20200201T080000Z","end":"20200201T100000Z","tags":["first"]}
+  type: interval
+  before:
+  after: {"start":"20200202T080000Z","tags":["second"]}
+txn:
+  type: config
+  before: verbose = on
+  after: verbose = off
+"#;
+
+    #[test]
+    fn parses_transactions_and_actions() {
+        let journal: Journal = JOURNAL.parse().unwrap();
+
+        assert_eq!(journal.transactions().len(), 3);
+        assert_eq!(
+            journal.transactions()[0].actions,
+            vec![UndoAction::Interval {
+                before: None,
+                after: Some(interval("inc 20200201T080000Z - 20200201T090000Z # first")),
+            }]
+        );
+        assert_eq!(journal.transactions()[1].actions.len(), 2);
+        assert_eq!(
+            journal.transactions()[2].actions,
+            vec![UndoAction::Other {
+                kind: "config".to_owned(),
+                before: "verbose = on".to_owned(),
+                after: "verbose = off".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn reads_journals_written_by_timew() {
+        let journal: Journal = include_str!("../tests/fixtures/undo.data").parse().unwrap();
+
+        assert_eq!(
+            journal.transactions()[1].actions,
+            vec![
+                UndoAction::Interval {
+                    before: Some(interval("inc 20200201T080000Z # foo")),
+                    after: None,
+                },
+                UndoAction::Interval {
+                    before: None,
+                    after: Some(interval("inc 20200201T080000Z - 20200201T093000Z # foo")),
+                },
+            ]
+        );
+        let annotated = interval(
+            "inc 20200201T080000Z - 20200201T093000Z # bar foo # \"lunch with \\\"Bob\\\"\"",
+        );
+        assert_eq!(
+            journal.transactions()[3].actions[1],
+            UndoAction::Interval {
+                before: None,
+                after: Some(annotated.clone()),
+            }
+        );
+        assert_eq!(
+            journal.state_at(&[annotated], 1).unwrap(),
+            vec![interval("inc 20200201T080000Z # foo")]
+        );
+    }
+
+    #[test]
+    fn rejects_broken_journals() {
+        assert_eq!(
+            "txn:\n  type: interval\n  after: inc 20200201T080000Z\n".parse::<Journal>(),
+            Err(JournalError::Interval {
+                line_number: 3,
+                message: "invalid JSON at byte 0: expected a value".to_owned(),
+            })
+        );
+        assert_eq!(
+            "txn:\n  type: interval\n  after: {\"start\":\"today\"}\n"
+                .parse::<Journal>()
+                .map_err(|error| error.to_string()),
+            Err("line 3: invalid interval: start is not a timestamp: \"today\"".to_owned())
+        );
+        assert_eq!(
+            "txn:\n  before: {}\n".parse::<Journal>(),
+            Err(JournalError::UnexpectedLine {
+                line_number: 2,
+                line: "  before: {}".to_owned(),
+            })
+        );
+        assert_eq!(
+            "hello\n".parse::<Journal>().is_err(),
+            true,
+            "lines without a key are not valid"
+        );
+    }
+
+    #[test]
+    fn replays_state_at_each_transaction() {
+        let journal: Journal = JOURNAL.parse().unwrap();
+        let current = vec![
+            interval("inc 20200201T080000Z - 20200201T100000Z # first"),
+            interval("inc 20200202T080000Z # second"),
+        ];
+
+        assert_eq!(journal.state_at(&current, 3).unwrap(), current);
+        assert_eq!(journal.state_at(&current, 2).unwrap(), current);
+        assert_eq!(
+            journal.state_at(&current, 1).unwrap(),
+            vec![interval("inc 20200201T080000Z - 20200201T090000Z # first")]
+        );
+        assert_eq!(journal.state_at(&current, 0).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn replay_detects_diverged_database() {
+        let journal: Journal = JOURNAL.parse().unwrap();
+        let current = vec![interval("inc 20200202T080000Z # second")];
+
+        assert_eq!(
+            journal.state_at(&current, 0),
+            Err(JournalError::Diverged {
+                transaction: 1,
+                interval: interval("inc 20200201T080000Z - 20200201T100000Z # first"),
+            })
+        );
+    }
+
+    #[test]
+    fn loads_journal_file() {
+        let dir = TempDir::new("undo-load");
+        let path = dir.write("undo.data", JOURNAL);
+
+        assert_eq!(Journal::load(&path).unwrap().transactions().len(), 3);
+        assert_eq!(
+            Journal::load(&dir.path().join("missing.data")).unwrap(),
+            Journal::default()
+        );
+    }
+
+    #[test]
+    fn writes_journal_in_timewarrior_format() {
+        let journal: Journal = JOURNAL.parse().unwrap();
+
+        let written = journal.to_string();
+        assert_eq!(written.parse::<Journal>().unwrap(), journal);
+        assert_eq!(
+            written.lines().take(4).collect::<Vec<_>>(),
+            vec![
+                "txn:",
+                "  type: interval",
+                "  before: ",
+                "  after: {\"start\":\"20200201T080000Z\",\"end\":\"20200201T090000Z\",\"tags\":[\"first\"]}",
+            ]
+        );
+    }
+
+    #[test]
+    fn appends_transactions_to_file() {
+        let dir = TempDir::new("undo-append");
+        let path = dir.path().join("undo.data");
+        let journal: Journal = JOURNAL.parse().unwrap();
+
+        for transaction in journal.transactions() {
+            Journal::append(&path, transaction).unwrap();
+        }
+
+        assert_eq!(Journal::load(&path).unwrap(), journal);
+    }
+}
@@ -0,0 +1,566 @@
+use crate::database::{list_data_files, parse_file, DataFile, LegacyCopies};
+use crate::{DatabaseError, ParseOptions, TimeWarriorLine};
+use chrono::{DateTime, Utc};
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// How long to wait before reading files again that could not be read
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A change of the intervals in a data directory
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    /// A new interval, open or closed
+    IntervalAdded(TimeWarriorLine),
+    /// An open interval got its end, like after `timew stop`
+    IntervalClosed {
+        before: TimeWarriorLine,
+        after: TimeWarriorLine,
+    },
+    /// An interval with the same start changed in any other way
+    IntervalModified {
+        before: TimeWarriorLine,
+        after: TimeWarriorLine,
+    },
+    IntervalRemoved(TimeWarriorLine),
+    /// An interval is active again after none was
+    TrackingStarted(TimeWarriorLine),
+    /// No interval is active anymore
+    TrackingStopped,
+}
+
+/// The events turning the intervals `before` into `after`
+///
+/// Removed and added intervals sharing a start are reported as a single
+/// change. Interval events come first, followed by at most one tracking event.
+pub fn diff(before: &[TimeWarriorLine], after: &[TimeWarriorLine]) -> Vec<WatchEvent> {
+    let mut events = interval_events(before, after);
+    events.extend(tracking_event(
+        before.iter().any(TimeWarriorLine::is_active),
+        after.iter().find(|interval| interval.is_active()),
+    ));
+    events
+}
+
+/// The interval events of `diff`, without the tracking event
+fn interval_events(before: &[TimeWarriorLine], after: &[TimeWarriorLine]) -> Vec<WatchEvent> {
+    let old: HashSet<&TimeWarriorLine> = before.iter().collect();
+    let new: HashSet<&TimeWarriorLine> = after.iter().collect();
+
+    let mut removed: Vec<Option<&TimeWarriorLine>> = before
+        .iter()
+        .filter(|interval| !new.contains(interval))
+        .map(Some)
+        .collect();
+    // positions in `removed` by start, to pair them with added intervals
+    let mut by_start: HashMap<DateTime<Utc>, VecDeque<usize>> = HashMap::new();
+    for (index, interval) in removed.iter().enumerate() {
+        if let Some(interval) = interval {
+            by_start
+                .entry(interval.from())
+                .or_default()
+                .push_back(index);
+        }
+    }
+
+    let mut events = Vec::new();
+    for interval in after.iter().filter(|interval| !old.contains(interval)) {
+        let paired = by_start
+            .get_mut(&interval.from())
+            .and_then(VecDeque::pop_front)
+            .and_then(|index| removed[index].take());
+        let event = match paired {
+            Some(old) if old.is_active() && !interval.is_active() => WatchEvent::IntervalClosed {
+                before: old.clone(),
+                after: interval.clone(),
+            },
+            Some(old) => WatchEvent::IntervalModified {
+                before: old.clone(),
+                after: interval.clone(),
+            },
+            None => WatchEvent::IntervalAdded(interval.clone()),
+        };
+        events.push(event);
+    }
+    events.extend(
+        removed
+            .into_iter()
+            .flatten()
+            .map(|interval| WatchEvent::IntervalRemoved(interval.clone())),
+    );
+    events
+}
+
+fn tracking_event(was_active: bool, active: Option<&TimeWarriorLine>) -> Option<WatchEvent> {
+    match active {
+        Some(active) if !was_active => Some(WatchEvent::TrackingStarted(active.clone())),
+        None if was_active => Some(WatchEvent::TrackingStopped),
+        _ => None,
+    }
+}
+
+/// Watches a data directory and streams `WatchEvent`s
+///
+/// The operating system reports changed files through the `notify` crate,
+/// inotify on Linux, and a background thread parses only the monthly files
+/// whose content changed. The thread stops when the watcher is dropped.
+pub struct Watcher {
+    events: Receiver<WatchEvent>,
+    signals: Sender<Signal>,
+    thread: Option<JoinHandle<()>>,
+    /// notifications stop when this is dropped
+    _notifications: RecommendedWatcher,
+}
+
+/// What wakes the background thread
+enum Signal {
+    /// Files of the data directory changed, `None` if any may have
+    Changed(Option<Vec<OsString>>),
+    Stop,
+}
+
+impl Watcher {
+    /// Starts watching, the intervals present now produce no events
+    pub fn new<P: Into<PathBuf>>(data_dir: P) -> Result<Watcher, DatabaseError> {
+        let data_dir = data_dir.into();
+        let (signals, wakeups) = mpsc::channel();
+
+        // watching before reading the files, so no change in between is missed
+        let notifications = {
+            let signals = signals.clone();
+            let mut notifications = notify::recommended_watcher(move |result| {
+                if let Some(signal) = signal(result) {
+                    let _ = signals.send(signal);
+                }
+            })
+            .map_err(notify_error(&data_dir))?;
+            notifications
+                .watch(&data_dir, RecursiveMode::NonRecursive)
+                .map_err(notify_error(&data_dir))?;
+            notifications
+        };
+        let mut snapshot = Snapshot::new(data_dir);
+        snapshot.refresh(None)?;
+
+        let (sender, events) = mpsc::channel();
+        let thread = thread::spawn(move || run(snapshot, wakeups, sender));
+
+        Ok(Watcher {
+            events,
+            signals,
+            thread: Some(thread),
+            _notifications: notifications,
+        })
+    }
+
+    /// Waits for the next event
+    pub fn recv(&self) -> Option<WatchEvent> {
+        self.events.recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// The next event if there is one
+    pub fn try_recv(&self) -> Option<WatchEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        let _ = self.signals.send(Signal::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn notify_error(data_dir: &Path) -> impl FnOnce(notify::Error) -> DatabaseError {
+    let io_error = DatabaseError::io(data_dir.to_owned());
+    move |error| match error.kind {
+        notify::ErrorKind::Io(source) => io_error(source),
+        _ => io_error(io::Error::other(error)),
+    }
+}
+
+/// The signal for a notification, `None` if it changes nothing
+fn signal(result: notify::Result<notify::Event>) -> Option<Signal> {
+    let event = match result {
+        Ok(event) => event,
+        // notifications may have been lost, so look at every file
+        Err(_) => return Some(Signal::Changed(None)),
+    };
+    match event.kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => (),
+        // reading, like the watcher itself does
+        EventKind::Access(_) => return None,
+        _ => (),
+    }
+    if event.need_rescan() || event.paths.is_empty() {
+        return Some(Signal::Changed(None));
+    }
+    let names = event
+        .paths
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(ToOwned::to_owned)
+        .collect();
+    Some(Signal::Changed(Some(names)))
+}
+
+fn run(mut snapshot: Snapshot, wakeups: Receiver<Signal>, sender: Sender<WatchEvent>) {
+    let mut retry = false;
+    loop {
+        let first = if retry {
+            match wakeups.recv_timeout(RETRY_INTERVAL) {
+                Ok(signal) => Some(signal),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        } else {
+            match wakeups.recv() {
+                Ok(signal) => Some(signal),
+                Err(_) => return,
+            }
+        };
+
+        // a single write causes several notifications, handle them together
+        let mut names = if retry { None } else { Some(HashSet::new()) };
+        for signal in first
+            .into_iter()
+            .chain(iter::from_fn(|| wakeups.try_recv().ok()))
+        {
+            match signal {
+                Signal::Stop => return,
+                Signal::Changed(None) => names = None,
+                Signal::Changed(Some(changed)) => {
+                    if let Some(names) = &mut names {
+                        names.extend(changed);
+                    }
+                }
+            }
+        }
+
+        // a file may be unreadable for a moment while it is replaced
+        match snapshot.refresh(names.as_ref()) {
+            Ok(events) => {
+                retry = false;
+                for event in events {
+                    if sender.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(_) => retry = true,
+        }
+    }
+}
+
+/// What the watcher last read from a monthly file
+struct FileState {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// tells rewrites apart that keep the size and the modification time
+    hash: u64,
+    intervals: Vec<TimeWarriorLine>,
+}
+
+/// The last seen state of every monthly file, by file name
+struct Snapshot {
+    data_dir: PathBuf,
+    files: HashMap<OsString, FileState>,
+}
+
+impl Snapshot {
+    fn new(data_dir: PathBuf) -> Self {
+        Snapshot {
+            data_dir,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Reads the files that changed and returns the events for them
+    ///
+    /// Files named in `hints`, or all files if there are none, are read
+    /// again, others only if their size or modification time changed.
+    fn refresh(
+        &mut self,
+        hints: Option<&HashSet<OsString>>,
+    ) -> Result<Vec<WatchEvent>, DatabaseError> {
+        let mut present = HashSet::new();
+        let mut changed: Vec<(OsString, Option<FileState>)> = Vec::new();
+
+        for file in list_data_files(&self.data_dir)? {
+            let name = file.path.file_name().unwrap_or_default().to_owned();
+            let (modified, len) = match fs::metadata(&file.path) {
+                Ok(metadata) => (metadata.modified().ok(), metadata.len()),
+                Err(_) => (None, 0),
+            };
+            let old = self.files.get_mut(&name);
+            present.insert(name.clone());
+
+            let hinted = hints.is_none_or(|hints| hints.contains(&name));
+            if !hinted
+                && old
+                    .as_ref()
+                    .is_some_and(|old| (old.modified, old.len) == (modified, len))
+            {
+                continue;
+            }
+
+            let content = fs::read_to_string(&file.path).map_err(DatabaseError::io(&file.path))?;
+            let hash = {
+                let mut hasher = DefaultHasher::new();
+                content.hash(&mut hasher);
+                hasher.finish()
+            };
+            match old {
+                Some(old) if old.hash == hash => {
+                    old.modified = modified;
+                    old.len = len;
+                }
+                _ => {
+                    let intervals = parse_file(
+                        &file.path,
+                        &content,
+                        ParseOptions::strict(),
+                        &mut Vec::new(),
+                        &mut Vec::new(),
+                    );
+                    let state = FileState {
+                        modified,
+                        len,
+                        hash,
+                        intervals,
+                    };
+                    changed.push((name, Some(state)));
+                }
+            }
+        }
+        for name in self.files.keys().filter(|name| !present.contains(*name)) {
+            changed.push((name.clone(), None));
+        }
+        if changed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let was_active = self.active().is_some();
+        let names: HashSet<OsString> = changed.iter().map(|(name, _)| name.clone()).collect();
+        let before = self.intervals_of(&names);
+        for (name, state) in changed {
+            match state {
+                Some(state) => self.files.insert(name, state),
+                None => self.files.remove(&name),
+            };
+        }
+        let after = self.intervals_of(&names);
+
+        let mut events = interval_events(&before, &after);
+        events.extend(tracking_event(was_active, self.active()));
+        Ok(events)
+    }
+
+    /// The intervals of the files in `names`, without the copies older
+    /// timewarrior versions made of intervals from earlier months
+    fn intervals_of(&self, names: &HashSet<OsString>) -> Vec<TimeWarriorLine> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by_key(|(name, _)| *name);
+
+        let mut copies = LegacyCopies::default();
+        let mut intervals = Vec::new();
+        for (name, state) in files {
+            let month = DataFile::from_path(PathBuf::from(name)).map(|file| file.month());
+            for interval in &state.intervals {
+                let copy = month.is_some_and(|month| copies.is_copy(month, interval));
+                if !copy && names.contains(name) {
+                    intervals.push(interval.clone());
+                }
+            }
+        }
+        intervals
+    }
+
+    fn active(&self) -> Option<&TimeWarriorLine> {
+        self.files
+            .values()
+            .flat_map(|state| &state.intervals)
+            .find(|interval| interval.is_active())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::test_util::{interval, TempDir};
+    use crate::Database;
+    use std::slice;
+    use std::time::Instant;
+
+    #[test]
+    fn diff_detects_start_and_stop() {
+        let closed = interval("inc 20200201T080000Z - 20200201T090000Z # first");
+        let open = interval("inc 20200201T100000Z # second");
+        let stopped = interval("inc 20200201T100000Z - 20200201T110000Z # second");
+
+        assert_eq!(
+            diff(slice::from_ref(&closed), &[closed.clone(), open.clone()]),
+            vec![
+                WatchEvent::IntervalAdded(open.clone()),
+                WatchEvent::TrackingStarted(open.clone()),
+            ]
+        );
+        assert_eq!(
+            diff(
+                &[closed.clone(), open.clone()],
+                &[closed.clone(), stopped.clone()]
+            ),
+            vec![
+                WatchEvent::IntervalClosed {
+                    before: open,
+                    after: stopped.clone(),
+                },
+                WatchEvent::TrackingStopped,
+            ]
+        );
+        let unchanged = slice::from_ref(&closed);
+        assert_eq!(diff(unchanged, unchanged), vec![]);
+    }
+
+    #[test]
+    fn diff_detects_modified_and_removed() {
+        let first = interval("inc 20200201T080000Z - 20200201T090000Z # first");
+        let retagged = interval("inc 20200201T080000Z - 20200201T090000Z # renamed");
+        let second = interval("inc 20200202T080000Z - 20200202T090000Z # second");
+
+        assert_eq!(
+            diff(&[first.clone(), second.clone()], slice::from_ref(&retagged)),
+            vec![
+                WatchEvent::IntervalModified {
+                    before: first,
+                    after: retagged,
+                },
+                WatchEvent::IntervalRemoved(second),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_copies_are_reported_once() {
+        let dir = TempDir::new("watch-legacy");
+        let line = "inc 20200131T220000Z - 20200201T020000Z # night\n";
+        dir.write("data/2020-01.data", line);
+        dir.write("data/2020-02.data", line);
+        let mut snapshot = Snapshot::new(dir.path().join("data"));
+
+        assert_eq!(
+            snapshot.refresh(None).unwrap(),
+            vec![WatchEvent::IntervalAdded(interval(line.trim_end()))]
+        );
+
+        dir.write(
+            "data/2020-02.data",
+            &format!("{}inc 20200202T080000Z - 20200202T090000Z # other\n", line),
+        );
+        assert_eq!(
+            snapshot.refresh(None).unwrap(),
+            vec![WatchEvent::IntervalAdded(interval(
+                "inc 20200202T080000Z - 20200202T090000Z # other"
+            ))]
+        );
+
+        fs::remove_file(dir.path().join("data/2020-01.data")).unwrap();
+        dir.write("data/2020-02.data", "");
+        assert_eq!(
+            snapshot.refresh(None).unwrap(),
+            vec![
+                WatchEvent::IntervalRemoved(interval(line.trim_end())),
+                WatchEvent::IntervalRemoved(interval(
+                    "inc 20200202T080000Z - 20200202T090000Z # other"
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn watcher_streams_changes_of_the_data_dir() {
+        let dir = TempDir::new("watch");
+        dir.write(
+            "data/2020-02.data",
+            "inc 20200201T080000Z - 20200201T090000Z # first\n",
+        );
+        let watcher = Watcher::new(dir.path().join("data")).unwrap();
+        assert_eq!(watcher.recv_timeout(Duration::from_millis(50)), None);
+
+        let mut database = Database::open(dir.path().join("data")).unwrap();
+        database
+            .add(interval("inc 20200301T080000Z # tracking"))
+            .unwrap();
+
+        assert_eq!(
+            watcher.recv_timeout(Duration::from_secs(5)),
+            Some(WatchEvent::IntervalAdded(interval(
+                "inc 20200301T080000Z # tracking"
+            )))
+        );
+        assert_eq!(
+            watcher.recv_timeout(Duration::from_secs(5)),
+            Some(WatchEvent::TrackingStarted(interval(
+                "inc 20200301T080000Z # tracking"
+            )))
+        );
+        assert_eq!(watcher.recv_timeout(Duration::from_millis(50)), None);
+    }
+
+    #[test]
+    fn watcher_sees_rewrites_of_the_same_size() {
+        let dir = TempDir::new("watch-retag");
+        let path = dir.write("data/2020-02.data", "inc 20200201T080000Z # aaa\n");
+        let watcher = Watcher::new(dir.path().join("data")).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        fs::write(&path, "inc 20200201T080000Z # bbb\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(modified))
+            .unwrap();
+
+        assert_eq!(
+            watcher.recv_timeout(Duration::from_secs(5)),
+            Some(WatchEvent::IntervalModified {
+                before: interval("inc 20200201T080000Z # aaa"),
+                after: interval("inc 20200201T080000Z # bbb"),
+            })
+        );
+    }
+
+    #[test]
+    fn dropping_the_watcher_stops_it_at_once() {
+        let dir = TempDir::new("watch-drop");
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        let watcher = Watcher::new(dir.path().join("data")).unwrap();
+
+        let start = Instant::now();
+        drop(watcher);
+
+        assert_eq!(start.elapsed() < Duration::from_secs(1), true);
+    }
+}